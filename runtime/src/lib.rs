@@ -0,0 +1,279 @@
+// Copyright (c) 2018 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! The run-time half of [`rusty-asm`](https://crates.io/crates/rusty-asm). Since `rusty-asm` is a procedural macro crate,
+//! it can't export traits or types, so they live here instead, along with a re-export of the `rusty_asm!` macro.
+//!
+//! The main item is the [`AsmOperand`] trait, which describes a type that can be passed to or from ASM code in a
+//! register. A bridge variable of such a type can leave the conversion to the trait by giving `_` as its register type:
+//!
+//! ```ignore
+//! use rusty_asm_runtime::{rusty_asm, AsmOperand};
+//!
+//! #[derive(Clone, Copy, AsmOperand)]
+//! #[repr(transparent)]
+//! struct Handle(u32);
+//!
+//! # unsafe fn close(handle: Handle) {
+//! rusty_asm! {
+//!     let handle: Handle as _: in(reg) = handle;
+//!     // ...
+//! }
+//! # }
+//! ```
+//!
+//! The macro checks at compile time that the type can be passed in the register class that the constraint asks for,
+//! according to [`AsmOperand::REGISTER_CLASSES`].
+//!
+//! `AsmOperand` can be derived for a `#[repr(transparent)]` struct whose field implements it (along with any number of
+//! `PhantomData` fields), or for a fieldless enum with an integer representation, like `#[repr(u8)]`.
+
+#![no_std]
+
+// Lets the code that `#[derive(AsmOperand)]` produces refer to this crate by name in its own tests.
+extern crate self as rusty_asm_runtime;
+
+pub use rusty_asm::{rusty_asm, AsmOperand};
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// A type that can be passed to or from ASM code in a register
+///
+/// # Safety
+///
+/// `Register` has to be a type that the compiler can put in every class in `REGISTER_CLASSES`, and `from_register` has
+/// to accept every value that `to_register` produces.
+pub unsafe trait AsmOperand: Copy {
+    /// The type that the value has while it's in a register
+    type Register: Copy;
+
+    /// Every class of registers that the value can be passed in
+    const REGISTER_CLASSES: &'static [RegisterClass];
+
+    /// Converts the value to the type it has in a register.
+    fn to_register(self) -> Self::Register;
+
+    /// Converts a value back from the type it has in a register.
+    ///
+    /// # Safety
+    ///
+    /// The value has to be one that `to_register` could have produced, unless the type says otherwise.
+    unsafe fn from_register(register: Self::Register) -> Self;
+}
+
+/// A class of registers, with the same names that `rusty_asm!` uses in declarations like `in(xmm_reg)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterClass {
+    /// `reg`: a general-purpose register
+    Reg,
+    /// `xmm_reg`: a 128-bit SSE register (x86 and x86-64)
+    XmmReg,
+    /// `ymm_reg`: a 256-bit AVX register (x86 and x86-64)
+    YmmReg,
+    /// `zmm_reg`: a 512-bit AVX-512 register (x86 and x86-64)
+    ZmmReg,
+    /// `kreg`: an AVX-512 mask register (x86 and x86-64)
+    KReg,
+    /// `vreg`: a NEON register (AArch64)
+    VReg,
+    /// `qreg`: a NEON register holding 128 bits (AArch64)
+    QReg,
+    /// `dreg`: a NEON register holding 64 bits (AArch64)
+    DReg,
+    /// `rvv_reg`: a vector register (RISC-V)
+    RvvReg
+}
+
+impl RegisterClass {
+    /// Determines whether this class is in the list. This can be used in constants, which is how `rusty_asm!` checks
+    /// bridge variables at compile time.
+    pub const fn is_in(self, classes: &[RegisterClass]) -> bool {
+        let mut i = 0;
+        while i < classes.len() {
+            if classes[i] as u8 == self as u8 {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+}
+
+/// Lets `rusty_asm!` check at compile time that a type can be passed in a class of registers, even in generic code
+///
+/// There's a constant for each variant of [`RegisterClass`], which fails to evaluate unless the class is in
+/// `T::REGISTER_CLASSES`. The macro refers to the constant inside the function, so the check happens once `T` is known.
+#[doc(hidden)]
+pub struct ClassCheck<T>(PhantomData<T>);
+
+macro_rules! class_checks {
+    ($($name:ident => $class:ident),*) => {
+        impl<T: AsmOperand> ClassCheck<T> {
+            $(
+                // Indexing out of bounds is a compile-time error in a constant.
+                pub const $name: () = [()][!RegisterClass::$class.is_in(T::REGISTER_CLASSES) as usize];
+            )*
+        }
+    };
+}
+
+class_checks!(REG => Reg, XMM_REG => XmmReg, YMM_REG => YmmReg, ZMM_REG => ZmmReg, K_REG => KReg, V_REG => VReg,
+    Q_REG => QReg, D_REG => DReg, RVV_REG => RvvReg);
+
+// Types that are already in the right form to be put in a register
+macro_rules! impl_asm_operand {
+    ($($ty:ty),* => $class:ident) => {
+        $(
+            unsafe impl AsmOperand for $ty {
+                type Register = Self;
+                const REGISTER_CLASSES: &'static [RegisterClass] = &[RegisterClass::$class];
+
+                fn to_register(self) -> Self {
+                    self
+                }
+
+                unsafe fn from_register(register: Self) -> Self {
+                    register
+                }
+            }
+        )*
+    };
+}
+
+impl_asm_operand!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize => Reg);
+
+unsafe impl<T: ?Sized> AsmOperand for *const T {
+    type Register = Self;
+    const REGISTER_CLASSES: &'static [RegisterClass] = &[RegisterClass::Reg];
+
+    fn to_register(self) -> Self {
+        self
+    }
+
+    unsafe fn from_register(register: Self) -> Self {
+        register
+    }
+}
+
+unsafe impl<T: ?Sized> AsmOperand for *mut T {
+    type Register = Self;
+    const REGISTER_CLASSES: &'static [RegisterClass] = &[RegisterClass::Reg];
+
+    fn to_register(self) -> Self {
+        self
+    }
+
+    unsafe fn from_register(register: Self) -> Self {
+        register
+    }
+}
+
+// Any nonzero value is `true`, so the conversion back is always safe.
+unsafe impl AsmOperand for bool {
+    type Register = u8;
+    const REGISTER_CLASSES: &'static [RegisterClass] = &[RegisterClass::Reg];
+
+    fn to_register(self) -> u8 {
+        self as u8
+    }
+
+    unsafe fn from_register(register: u8) -> Self {
+        register != 0
+    }
+}
+
+// The ASM code must never produce a null pointer.
+unsafe impl<T> AsmOperand for NonNull<T> {
+    type Register = *mut T;
+    const REGISTER_CLASSES: &'static [RegisterClass] = &[RegisterClass::Reg];
+
+    fn to_register(self) -> *mut T {
+        self.as_ptr()
+    }
+
+    unsafe fn from_register(register: *mut T) -> Self {
+        NonNull::new_unchecked(register)
+    }
+}
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__m128, __m128d, __m128i, __m256, __m256d, __m256i};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__m128, __m128d, __m128i, __m256, __m256d, __m256i};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl_asm_operand!(__m128, __m128d, __m128i => XmmReg);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl_asm_operand!(__m256, __m256d, __m256i => YmmReg);
+
+#[cfg(test)]
+mod tests {
+    use super::{AsmOperand, ClassCheck, RegisterClass};
+    use core::marker::PhantomData;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, AsmOperand)]
+    #[repr(transparent)]
+    struct Handle(u32);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, AsmOperand)]
+    #[repr(transparent)]
+    struct Named<T: Copy> {
+        ptr: *const T
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, AsmOperand)]
+    #[repr(transparent)]
+    struct Tagged<T: Copy>(PhantomData<T>, u64, PhantomData<*const T>);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, AsmOperand)]
+    #[repr(u16)]
+    enum Mode {
+        Read = 1,
+        Write = 0x200
+    }
+
+    #[test]
+    fn derived() {
+        assert_eq!(Handle(7).to_register(), 7u32);
+        assert_eq!(unsafe { Handle::from_register(9) }, Handle(9));
+        assert_eq!(Handle::REGISTER_CLASSES, [RegisterClass::Reg]);
+
+        let x = 3u8;
+        let named = Named { ptr: &x };
+        assert_eq!(named.to_register(), &x as *const u8);
+        assert_eq!(unsafe { Named::<u8>::from_register(&x) }, named);
+
+        let tagged = Tagged::<u8>(PhantomData, 5, PhantomData);
+        assert_eq!(tagged.to_register(), 5u64);
+        assert_eq!(unsafe { Tagged::<u8>::from_register(5) }, tagged);
+
+        assert_eq!(Mode::Write.to_register(), 0x200u16);
+        assert_eq!(unsafe { Mode::from_register(1) }, Mode::Read);
+        assert_eq!(Mode::REGISTER_CLASSES, [RegisterClass::Reg]);
+    }
+
+    // This is the check that `rusty_asm!` emits for `let x: T as _: in(reg)`.
+    fn check_reg<T: AsmOperand>() {
+        let _ = ClassCheck::<T>::REG;
+    }
+
+    #[test]
+    fn register_classes() {
+        check_reg::<Handle>();
+        check_reg::<Named<u8>>();
+        assert!(RegisterClass::Reg.is_in(bool::REGISTER_CLASSES));
+        assert!(!RegisterClass::XmmReg.is_in(usize::REGISTER_CLASSES));
+        assert!(unsafe { bool::from_register(2) });
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright (c) 2018 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! `#[cfg]` conditions on bridge variables, clobbers, and `asm` blocks. A procedural macro can't tell which
+//! configuration is being compiled, so the conditions are kept symbolically, and the generated code has a separate
+//! `asm!` invocation for each configuration that needs one.
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{ToTokens, TokenStreamExt};
+use syn::{Attribute, Meta, MetaList, NestedMeta};
+use syn::parse::{self, ParseStream, Parser};
+
+/// The most predicates that can affect one `asm` block. Every combination of them is considered, so this keeps the
+/// expansion from taking too long.
+pub const MAX_PREDICATES: usize = 10;
+
+/// A condition that's written like the inside of a `#[cfg]` attribute.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// A single predicate, like `unix` or `target_feature = "avx2"`
+    Predicate(TokenStream),
+    Not(Box<Condition>),
+    All(Vec<Condition>),
+    Any(Vec<Condition>)
+}
+
+impl Condition {
+    fn from_nested_meta(nested: &NestedMeta) -> Self {
+        match nested {
+            NestedMeta::Meta(meta) => Condition::from_meta(meta),
+            NestedMeta::Literal(lit) => Condition::Predicate(lit.into_token_stream())
+        }
+    }
+
+    fn from_meta(meta: &Meta) -> Self {
+        let nested = |list: &MetaList| list.nested.iter().map(Condition::from_nested_meta).collect::<Vec<_>>();
+        match meta {
+            Meta::List(list) if list.ident == "all" => Condition::All(nested(list)),
+            Meta::List(list) if list.ident == "any" => Condition::Any(nested(list)),
+            Meta::List(list) if list.ident == "not" && list.nested.len() == 1 => {
+                Condition::Not(Box::new(nested(list).remove(0)))
+            },
+            _ => Condition::Predicate(meta.into_token_stream())
+        }
+    }
+
+    /// Returns a condition that holds when both of the given conditions do. `None` always holds.
+    pub fn and(a: Option<Condition>, b: Condition) -> Condition {
+        match a {
+            Some(a) => Condition::All(vec![a, b]),
+            None => b
+        }
+    }
+
+    /// Adds the predicates in this condition to the list, unless they're already in it.
+    pub fn collect_predicates(&self, predicates: &mut Vec<String>) {
+        match self {
+            Condition::Predicate(tokens) => {
+                let predicate = tokens.to_string();
+                if !predicates.contains(&predicate) {
+                    predicates.push(predicate);
+                }
+            },
+            Condition::Not(condition) => condition.collect_predicates(predicates),
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                for condition in conditions.iter() {
+                    condition.collect_predicates(predicates);
+                }
+            }
+        }
+    }
+
+    /// Determines whether the condition holds if each of the predicates has the corresponding value. Every predicate in
+    /// the condition has to be in the list.
+    pub fn holds(&self, predicates: &[String], values: &[bool]) -> bool {
+        match self {
+            Condition::Predicate(tokens) => {
+                let predicate = tokens.to_string();
+                values[predicates.iter().position(|other| *other == predicate).unwrap()]
+            },
+            Condition::Not(condition) => !condition.holds(predicates, values),
+            Condition::All(conditions) => conditions.iter().all(|condition| condition.holds(predicates, values)),
+            Condition::Any(conditions) => conditions.iter().any(|condition| condition.holds(predicates, values))
+        }
+    }
+
+    /// Determines whether there's any configuration where both conditions hold. `None` always holds.
+    pub fn may_hold_together(a: Option<&Condition>, b: Option<&Condition>) -> bool {
+        let mut predicates = Vec::new();
+        for condition in a.iter().chain(b.iter()) {
+            condition.collect_predicates(&mut predicates);
+        }
+        if predicates.len() > MAX_PREDICATES {
+            return true;
+        }
+        let holds = |condition: Option<&Condition>, values: &[bool]| match condition {
+            Some(condition) => condition.holds(&predicates, values),
+            None => true
+        };
+        configurations(predicates.len()).any(|values| holds(a, &values) && holds(b, &values))
+    }
+
+    /// Returns the condition that holds in exactly the given configuration of the predicates.
+    pub fn configuration(predicates: &[String], values: &[bool]) -> Condition {
+        Condition::All(predicates.iter().zip(values.iter()).map(|(predicate, &value)| {
+            let predicate = Condition::Predicate(predicate.parse().unwrap());
+            if value { predicate } else { Condition::Not(Box::new(predicate)) }
+        }).collect())
+    }
+}
+
+impl ToTokens for Condition {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.append_all(match self {
+            Condition::Predicate(predicate) => predicate.clone(),
+            Condition::Not(condition) => quote!(not(#condition)),
+            Condition::All(conditions) => quote!(all(#(#conditions),*)),
+            Condition::Any(conditions) => quote!(any(#(#conditions),*))
+        });
+    }
+}
+
+/// Returns every combination of values for the given number of predicates.
+pub fn configurations(count: usize) -> impl Iterator<Item = Vec<bool>> {
+    (0 .. 1u32 << count).map(move |bits| (0 .. count).map(|i| bits & (1 << i) != 0).collect())
+}
+
+/// The outer attributes on a bridge variable declaration, a clobber, or an `asm` block
+#[derive(Debug, Clone, Default)]
+pub struct OuterAttributes {
+    /// The condition given by `#[cfg]` attributes, including any inside `#[cfg_attr]`, if there are any
+    pub cfg: Option<Condition>,
+    /// The other attributes, which are passed on to the Rust code
+    pub others: Vec<Attribute>
+}
+
+impl OuterAttributes {
+    pub fn parse(input: ParseStream) -> parse::Result<Self> {
+        let mut attrs = OuterAttributes::default();
+        for attr in input.call(Attribute::parse_outer)? {
+            if is_named(&attr, "cfg") {
+                match attr.parse_meta()? {
+                    Meta::List(ref list) if list.nested.len() == 1 => {
+                        attrs.add_cfg(Condition::from_nested_meta(&list.nested[0]));
+                    },
+                    _ => return Err(parse::Error::new_spanned(&attr, "expected `#[cfg(<predicate>)]`"))
+                }
+            } else if is_named(&attr, "cfg_attr") {
+                attrs.add_cfg_attr(attr)?;
+            } else {
+                attrs.others.push(attr);
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn add_cfg(&mut self, condition: Condition) {
+        self.cfg = Some(Condition::and(self.cfg.take(), condition));
+    }
+
+    // `#[cfg_attr(<predicate>, <attributes>)]`. A `cfg` among the attributes only applies when the predicate holds. The
+    // other attributes stay in a `#[cfg_attr]` of their own.
+    fn add_cfg_attr(&mut self, mut attr: Attribute) -> parse::Result<()> {
+        let (predicate, bodies) = (|input: ParseStream| {
+            let content;
+            parenthesized!(content in input);
+            let predicate = content.parse::<Meta>()?;
+            let mut bodies = Vec::new();
+            while !content.is_empty() {
+                content.parse::<Token![,]>()?;
+                if content.is_empty() {
+                    break;
+                }
+                // Everything up to the next comma
+                bodies.push(content.step(|cursor| {
+                    let mut body = TokenStream::new();
+                    let mut rest = *cursor;
+                    while let Some((tt, next)) = rest.token_tree() {
+                        match tt {
+                            TokenTree::Punct(ref punct) if punct.as_char() == ',' => break,
+                            _ => {
+                                body.append(tt);
+                                rest = next;
+                            }
+                        };
+                    }
+                    Ok((body, rest))
+                })?);
+            }
+            Ok((predicate, bodies))
+        }).parse2(attr.tts.clone())?;
+
+        let mut others = Vec::new();
+        for body in bodies {
+            let meta = syn::parse2::<Meta>(body.clone()).ok();
+            match meta {
+                Some(Meta::List(ref list)) if list.ident == "cfg" && list.nested.len() == 1 => {
+                    let condition = Condition::from_nested_meta(&list.nested[0]);
+                    let predicate = Condition::from_meta(&predicate);
+                    self.add_cfg(Condition::Any(vec![Condition::Not(Box::new(predicate)), condition]));
+                },
+                _ => others.push(body)
+            }
+        }
+        if !others.is_empty() {
+            attr.tts = quote!((#predicate, #(#others),*));
+            self.others.push(attr);
+        }
+        Ok(())
+    }
+
+    /// Determines whether there are any attributes.
+    pub fn is_empty(&self) -> bool {
+        self.cfg.is_none() && self.others.is_empty()
+    }
+}
+
+impl ToTokens for OuterAttributes {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        if let Some(ref cfg) = self.cfg {
+            tokens.append_all(quote!(#[cfg(#cfg)]));
+        }
+        for attr in self.others.iter() {
+            attr.to_tokens(tokens);
+        }
+    }
+}
+
+fn is_named(attr: &Attribute, name: &str) -> bool {
+    attr.path.leading_colon.is_none() && attr.path.segments.len() == 1 && attr.path.segments[0].ident == name
+}
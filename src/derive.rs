@@ -0,0 +1,122 @@
+// Copyright (c) 2018 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! `#[derive(AsmOperand)]`, which implements `rusty_asm_runtime::AsmOperand` for a `#[repr(transparent)]` struct or a
+//! fieldless enum with an integer representation.
+
+use proc_macro2::TokenStream;
+use syn::{Data, DeriveInput, Fields, Ident, Member, Meta, NestedMeta, Type};
+use syn::parse;
+
+// The integer types that an enum can be represented as
+const INTEGER_REPRS: [&str; 10] = ["u8", "u16", "u32", "u64", "usize", "i8", "i16", "i32", "i64", "isize"];
+
+pub fn derive_asm_operand(mut input: DeriveInput) -> parse::Result<TokenStream> {
+    let reprs = reprs(&input);
+    let name = &input.ident;
+
+    match input.data {
+        Data::Struct(ref data) if reprs.iter().any(|repr| repr == "transparent") => {
+            // Everything is delegated to the one field that isn't a `PhantomData`.
+            let members = data.fields.iter().enumerate().map(|(i, field)| match field.ident {
+                Some(ref ident) => Member::Named(ident.clone()),
+                None => Member::Unnamed(i.into())
+            });
+            let (phantoms, fields) = members.zip(data.fields.iter())
+                .partition::<Vec<_>, _>(|&(_, field)| is_phantom_data(&field.ty));
+            let (member, field_type) = match fields[..] {
+                [(ref member, field)] => (member.clone(), field.ty.clone()),
+                _ => return Err(parse::Error::new(name.span(),
+                    "`AsmOperand` can only be derived for a struct with exactly one field that isn't a `PhantomData`"))
+            };
+            let phantoms = phantoms.iter().map(|(member, _)| member);
+            input.generics.make_where_clause().predicates
+                .push(parse_quote!(#field_type: ::rusty_asm_runtime::AsmOperand));
+            let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+            Ok(quote! {
+                unsafe impl #impl_generics ::rusty_asm_runtime::AsmOperand for #name #type_generics #where_clause {
+                    type Register = <#field_type as ::rusty_asm_runtime::AsmOperand>::Register;
+                    const REGISTER_CLASSES: &'static [::rusty_asm_runtime::RegisterClass] =
+                        <#field_type as ::rusty_asm_runtime::AsmOperand>::REGISTER_CLASSES;
+
+                    fn to_register(self) -> Self::Register {
+                        ::rusty_asm_runtime::AsmOperand::to_register(self.#member)
+                    }
+
+                    unsafe fn from_register(register: Self::Register) -> Self {
+                        #name {
+                            #member: <#field_type as ::rusty_asm_runtime::AsmOperand>::from_register(register),
+                            #(#phantoms: ::core::marker::PhantomData),*
+                        }
+                    }
+                }
+            })
+        },
+
+        Data::Enum(ref data) => {
+            // The enum is converted to its integer representation and back.
+            let repr = reprs.iter().find(|repr| INTEGER_REPRS.contains(&repr.to_string().as_str()))
+                .ok_or_else(|| parse::Error::new(name.span(),
+                    "`AsmOperand` can only be derived for an enum with an integer representation, like `#[repr(u8)]`"))?;
+            if let Some(variant) = data.variants.iter().find(|variant| !matches!(variant.fields, Fields::Unit)) {
+                return Err(parse::Error::new(variant.ident.span(),
+                    "`AsmOperand` can only be derived for an enum whose variants don't have any fields"));
+            }
+            let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+            Ok(quote! {
+                unsafe impl #impl_generics ::rusty_asm_runtime::AsmOperand for #name #type_generics #where_clause {
+                    type Register = #repr;
+                    const REGISTER_CLASSES: &'static [::rusty_asm_runtime::RegisterClass] =
+                        &[::rusty_asm_runtime::RegisterClass::Reg];
+
+                    fn to_register(self) -> #repr {
+                        self as #repr
+                    }
+
+                    // The ASM code has to produce the discriminant of one of the variants.
+                    unsafe fn from_register(register: #repr) -> Self {
+                        ::core::mem::transmute::<#repr, Self>(register)
+                    }
+                }
+            })
+        },
+
+        _ => Err(parse::Error::new(name.span(),
+            "`AsmOperand` can only be derived for a `#[repr(transparent)]` struct or an enum with an integer \
+            representation, like `#[repr(u8)]`"))
+    }
+}
+
+// Determines whether the type is a `PhantomData`, which takes up no space in a `#[repr(transparent)]` struct.
+fn is_phantom_data(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path.qself.is_none()
+            && path.path.segments.last().map_or(false, |segment| segment.value().ident == "PhantomData"),
+        _ => false
+    }
+}
+
+// Returns everything listed in the type's `#[repr]` attributes.
+fn reprs(input: &DeriveInput) -> Vec<Ident> {
+    input.attrs.iter()
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(ref list)) if list.ident == "repr" => Some(list.nested.clone()),
+            _ => None
+        })
+        .flat_map(|nested| nested.into_iter())
+        .filter_map(|nested| match nested {
+            NestedMeta::Meta(Meta::Word(ident)) => Some(ident),
+            _ => None
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+// Copyright (c) 2018 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! The macro's own diagnostics: named lints with configurable levels, and helpers for pointing diagnostics at specific
+//! parts of a string literal, like a single bridge variable reference in a long `asm` block.
+
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+use proc_macro2::Span;
+use syn::{Ident, LitStr};
+use syn::parse;
+
+/// The warnings that `rusty_asm!` can produce. Each one can be allowed, warned about, or denied separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A bridge variable isn't used by any `asm` block in its scope.
+    UnusedBridgeVar,
+    /// An `asm` block writes to an output, but nothing reads the value afterward.
+    UnreadOutput,
+    /// An output's register covers a clobber.
+    ClobberOverlap,
+    /// A `$` in the ASM code isn't followed by an identifier or another `$`.
+    StrayDollar,
+    /// A `$<ident>` in the ASM code doesn't name a bridge variable.
+    UnrecognizedBridgeVar,
+    /// An `asm` block gets a pointer to mutable memory without declaring a memory clobber, so one was added.
+    ImplicitMemoryClobber
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedBridgeVar,
+        Lint::UnreadOutput,
+        Lint::ClobberOverlap,
+        Lint::StrayDollar,
+        Lint::UnrecognizedBridgeVar,
+        Lint::ImplicitMemoryClobber
+    ];
+
+    /// The name of the lint group that contains every lint.
+    pub const GROUP: &'static str = "rusty_asm";
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedBridgeVar       => "unused_bridge_var",
+            Lint::UnreadOutput          => "unread_output",
+            Lint::ClobberOverlap        => "clobber_overlap",
+            Lint::StrayDollar           => "stray_dollar",
+            Lint::UnrecognizedBridgeVar => "unrecognized_bridge_var",
+            Lint::ImplicitMemoryClobber => "implicit_memory_clobber"
+        }
+    }
+}
+
+/// How a lint is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "allow" => Some(Level::Allow),
+            "warn"  => Some(Level::Warn),
+            "deny"  => Some(Level::Deny),
+            _       => None
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Allow => write!(f, "allow"),
+            Level::Warn  => write!(f, "warn"),
+            Level::Deny  => write!(f, "deny")
+        }
+    }
+}
+
+/// The level of every lint at some point in a `rusty_asm!` invocation. Like bridge variables, lint levels are scoped to
+/// the block that sets them.
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    // `None` means the lint is at its default level.
+    levels: [Option<Level>; 6]
+}
+
+impl LintLevels {
+    // With the `deny-warnings` feature, every lint is an error unless it's explicitly set to something else.
+    #[cfg(not(feature = "deny-warnings"))]
+    const DEFAULT_LEVEL: Level = Level::Warn;
+    #[cfg(feature = "deny-warnings")]
+    const DEFAULT_LEVEL: Level = Level::Deny;
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels[lint as usize].unwrap_or(Self::DEFAULT_LEVEL)
+    }
+
+    /// Sets the level of the named lint or lint group. Returns `false` if there's no such lint.
+    pub fn set(&mut self, name: &Ident, level: Level) -> bool {
+        if name == Lint::GROUP {
+            for lint in Lint::ALL.iter() {
+                self.levels[*lint as usize] = Some(level);
+            }
+            true
+        } else if let Some(lint) = Lint::ALL.iter().find(|lint| name == lint.name()) {
+            self.levels[*lint as usize] = Some(level);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reports the given lint at its current level. Denied lints are added to `errors`, with the help message and note
+    /// attached.
+    pub fn emit<T: Display>(&self, lint: Lint, span: Span, message: T, help_span: Span, help_message: &str,
+            errors: &mut Vec<parse::Error>) {
+        let level = self.level(lint);
+        let note = if self.levels[lint as usize].is_some() {
+            format!("`#[{}({})]` is in effect", level, lint.name())
+        } else {
+            format!("`#[{}({})]` on by default", level, lint.name())
+        };
+        match level {
+            Level::Allow => {},
+            Level::Warn => emit_warning(span, message.to_string(), help_span, help_message, note),
+            Level::Deny => {
+                errors.push(error_with_details(span, message, help_span, help_message, Some(&note)));
+            }
+        }
+    }
+}
+
+/// Makes an error with a help message attached. With the `proc-macro` feature, the help message is emitted on its own,
+/// like the ones the compiler attaches to its errors. Otherwise, it's added to the end of the error's message.
+pub fn error_with_help<T: Display>(span: Span, message: T, help_message: &str) -> parse::Error {
+    error_with_details(span, message, span, help_message, None)
+}
+
+/// Formats a help message to go at the end of an error's message, for errors that can't have diagnostics attached to
+/// them, like the ones passed to `compile_error!` for the compiler to report later.
+pub fn help_suffix(help_message: &str) -> String {
+    format!("\n= help: {}", help_message)
+}
+
+fn error_with_details<T: Display>(span: Span, message: T, help_span: Span, help_message: &str, note: Option<&str>)
+        -> parse::Error {
+    let mut message = message.to_string();
+    if !emit_details(help_span, help_message, note) {
+        message.push_str(&help_suffix(help_message));
+        if let Some(note) = note {
+            message.push_str(&format!("\n= note: {}", note));
+        }
+    }
+    parse::Error::new(span, message)
+}
+
+/// Returns a span for the given byte range of the literal's value, along with a note that should be appended to the
+/// diagnostic's message. If the compiler can give us a span for just that range, the note is empty. Otherwise, the span
+/// covers the whole literal, and the note is an excerpt of the code with carets pointing to the range.
+pub fn locate(lit: &LitStr, range: Range<usize>) -> (Span, String) {
+    match source_range(&lit_source(lit), range.clone()).and_then(|range| subspan(lit, range)) {
+        Some(span) => (span, String::new()),
+        None => (lit.span(), excerpt(&lit.value(), range))
+    }
+}
+
+/// Maps a byte range in the value of a string literal to the corresponding byte range in its source code, which also
+/// includes the quotes and any escape sequences. Returns `None` if the range doesn't line up with the source.
+pub fn source_range(source: &str, range: Range<usize>) -> Option<Range<usize>> {
+    if source.starts_with('r') {
+        // A raw string: the value is exactly the source minus the delimiters.
+        let prefix_len = source[1 ..].find('"')? + 2;
+        return Some(range.start + prefix_len .. range.end + prefix_len);
+    }
+
+    let mut value_offset = 0;
+    let mut start = None;
+    let mut end = None;
+    let mut chars = source.char_indices().skip(1).peekable(); // Skip the opening quote.
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' && chars.peek().map_or(false, |&(_, c)| c == '\n' || c == '\r') {
+            // An escaped newline removes itself and all the whitespace after it.
+            while let Some(&(_, c)) = chars.peek() {
+                if !c.is_whitespace() {
+                    break;
+                }
+                chars.next();
+            }
+            continue;
+        }
+
+        if value_offset == range.start && start.is_none() {
+            start = Some(i);
+        }
+        if value_offset == range.end && end.is_none() {
+            end = Some(i);
+        }
+        if i == source.len() - 1 {
+            break; // The closing quote
+        }
+
+        if c == '\\' {
+            match chars.next() {
+                Some((_, 'x')) => {
+                    chars.nth(1);
+                    value_offset += 1;
+                },
+                Some((_, 'u')) => {
+                    let mut digits = String::new();
+                    for (_, c) in chars.by_ref() {
+                        if c == '}' {
+                            break;
+                        } else if c != '{' && c != '_' {
+                            digits.push(c);
+                        }
+                    }
+                    let code_point = u32::from_str_radix(&digits, 16).ok().and_then(std::char::from_u32)?;
+                    value_offset += code_point.len_utf8();
+                },
+                Some(_) => value_offset += 1,
+                None => return None
+            }
+        } else {
+            value_offset += c.len_utf8();
+        }
+    }
+
+    match (start, end) {
+        (Some(start), Some(end)) => Some(start .. end),
+        _ => None
+    }
+}
+
+/// Renders the line of `code` that contains the start of `range`, with carets underneath the range, for when we can't
+/// point to the range directly.
+pub fn excerpt(code: &str, range: Range<usize>) -> String {
+    let line_start = code[.. range.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = code[range.start ..].find('\n').map_or(code.len(), |i| range.start + i);
+    let line_number = code[.. line_start].matches('\n').count() + 1;
+    let line = &code[line_start .. line_end];
+
+    // Keep any tabs so the carets line up, no matter how wide the tabs are.
+    let padding = code[line_start .. range.start].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let caret_count = code[range.start .. range.end.min(line_end)].chars().count().max(1);
+
+    format!("\n  --> line {} of the `asm` code\n   |\n   | {}\n   | {}{}", line_number, line, padding, "^".repeat(caret_count))
+}
+
+// Returns the literal exactly as it appears in the source code.
+fn lit_source(lit: &LitStr) -> String {
+    quote!(#lit).to_string()
+}
+
+#[cfg(all(feature = "proc-macro", not(test)))]
+fn subspan(lit: &LitStr, range: Range<usize>) -> Option<Span> {
+    let tokens = proc_macro::TokenStream::from(quote!(#lit));
+    match tokens.into_iter().next() {
+        Some(proc_macro::TokenTree::Literal(literal)) => literal.subspan(range).map(Span::from),
+        _ => None
+    }
+}
+
+#[cfg(not(all(feature = "proc-macro", not(test))))]
+fn subspan(_: &LitStr, _: Range<usize>) -> Option<Span> {
+    None
+}
+
+#[cfg(all(feature = "proc-macro", not(test)))]
+fn emit_warning(span: Span, message: String, help_span: Span, help_message: &str, note: String) {
+    span.unstable().warning(message).span_help(help_span.unstable(), help_message).note(note).emit();
+}
+
+#[cfg(not(all(feature = "proc-macro", not(test))))]
+fn emit_warning(_: Span, _: String, _: Span, _: &str, _: String) {}
+
+// Returns `false` if the details couldn't be emitted, so they need to go in the error's message instead.
+#[cfg(all(feature = "proc-macro", not(test)))]
+fn emit_details(help_span: Span, help_message: &str, note: Option<&str>) -> bool {
+    help_span.unstable().help(help_message).emit();
+    if let Some(note) = note {
+        help_span.unstable().note(note).emit();
+    }
+    true
+}
+
+#[cfg(not(all(feature = "proc-macro", not(test))))]
+fn emit_details(_: Span, _: &str, _: Option<&str>) -> bool {
+    false
+}
@@ -75,6 +75,23 @@
 //!
 //! Note that you'll still need a nightly compiler for this. `rusty_asm` doesn't make inline ASM stable.
 //!
+//! ### The Runtime Crate
+//!
+//! Since `rusty-asm` is a procedural macro crate, it can't export any traits or types. Those live in the companion crate,
+//! `rusty-asm-runtime`, which also re-exports `rusty_asm!`. Its main item is the `AsmOperand` trait, which describes a type
+//! that can be passed in a register: the type it has in the register, how to convert it, and which classes of registers
+//! it fits in. `AsmOperand` is implemented for the integer types, `bool`, raw pointers, `NonNull<T>`, and the SSE and AVX
+//! vector types, and it can be derived for a `#[repr(transparent)]` struct or for a fieldless enum with an integer
+//! representation, like `#[repr(u8)]`:
+//!
+//! ```ignore
+//! #[derive(Clone, Copy, AsmOperand)]
+//! #[repr(transparent)]
+//! struct Handle(u32);
+//! ```
+//!
+//! Code that uses `let <identifier>: <type> as _: ...` has to depend on `rusty-asm-runtime` under that name.
+//!
 //! ### Supported Features
 //!
 //! The following features are available:
@@ -83,6 +100,13 @@
 //!   [`proc_macro`](https://doc.rust-lang.org/proc_macro/index.html), including the parts that are still unstable.
 //!   The benefit of this feature is that it allows `rusty-asm` to provide its own warnings, which should make
 //!   debugging your own code easier.
+//! * `deny-warnings`: Turns all of `rusty-asm`'s warnings into errors, unless your code sets them to another level (see
+//!   [Lints](#lints) below). Since errors don't depend on the `proc-macro` feature, this also works on compilers that can't
+//!   show the warnings.
+//! * `flag-outputs`: Uses the compiler's own support for flag outputs (see [Basic Syntax](#basic-syntax)) instead of an
+//!   extra instruction. This needs a compiler based on LLVM 9 or later (LLVM 14 for AArch64).
+//! * `force-fallback`: Compiles the `fallback` block of every `asm` block that has one (see
+//!   [The `asm` Block](#the-asm-block)) instead of its ASM code, so the fallbacks can be tested on any target.
 //!
 //! ## Basic Syntax
 //!
@@ -113,6 +137,12 @@
 //! Also, it's possible to have multiple `asm` blocks in the same `rusty_asm!` block, in case you want to reuse your bridge
 //! variables (see below).
 //!
+//! If the macro finds a mistake in a bridge variable declaration, a clobber, or an `asm` block, it reports the error and skips to
+//! the end of that statement (the next `;`, the end of the `asm` block, or the end of the enclosing block). That way, every
+//! such mistake in the invocation is reported at once. Everything else is still expanded as usual, so the compiler (and your
+//! IDE) can check the rest of the code, too. An invalid bridge variable declaration is expanded as an ordinary `let`
+//! statement, and an invalid `asm` block is expanded as nothing at all.
+//!
 //! ## Bridge Variables
 //!
 //! A _bridge variable_ is a variable that bridges the gap between Rust and ASM by incorporating the input/ouput/clobber
@@ -135,6 +165,76 @@
 //! The optional `<type>` is any Rust type, as far as the macro knows, but it should be something that makes sense to put in the
 //! appropriate register (e.g. `usize`, `i8`, etc. for a general-purpose integer register).
 //!
+//! A variable can also have a different type in the register than it has in Rust, as long as both types are the same size.
+//! The register type is given after `as`:
+//!
+//! ```text
+//! let [mut] <identifier>: <type> as <register-type>: in(<constraint>) [= <expression>];
+//! ```
+//!
+//! This works for types like a `#[repr(u8)]` enum, `NonNull<T>`, or a `#[repr(transparent)]` wrapper around an integer. An
+//! input is transmuted to the register type when the `asm` block starts, and an output is transmuted back after the block
+//! ends, so the two types have to be the same size, and the ASM code has to leave a valid value of the Rust type in the
+//! register. Nothing checks that value: an out-of-range enum discriminant or a null `NonNull` is undefined behavior. A
+//! `bool` is the exception, since it's converted by value: an input is cast to the register type, and any nonzero output
+//! means `true`, so it can go in a register of any width. (To pass an enum in a wider register, cast it in the initializer
+//! instead, as in `let mode: usize: in("r") = mode as usize;`.) The variable should be `Copy`, since converting an input
+//! moves it.
+//!
+//! If the register type is `_`, the conversion is left to the type's implementation of the `AsmOperand` trait from the
+//! [`rusty-asm-runtime`](#the-runtime-crate) crate instead, and the type's `REGISTER_CLASSES` are checked at compile time
+//! against the register the variable is bound to.
+//!
+//! Instead of a constraint, a bridge variable can name the class of registers it belongs in, like `in(xmm_reg)`. Except for
+//! `reg`, the variable then needs an explicit type that fits in the class, which is one of the types from `core::arch`:
+//!
+//! * `reg`: a general-purpose register, with any type (every target)
+//! * `xmm_reg`: `__m128`, `__m128i`, or `__m128d` (x86 and x86-64)
+//! * `ymm_reg`: `__m256`, `__m256i`, or `__m256d` (x86 and x86-64)
+//! * `zmm_reg`: `__m512`, `__m512i`, or `__m512d` (x86 and x86-64)
+//! * `kreg`: an AVX-512 mask, `__mmask8` through `__mmask64` (x86 and x86-64)
+//! * `vreg`: any 64-bit or 128-bit NEON type, like `uint8x8_t` or `float32x4_t` (AArch64)
+//! * `qreg`: a 128-bit NEON type, referenced in the ASM code as `q<n>` (AArch64)
+//! * `dreg`: a 64-bit NEON type, referenced in the ASM code as `d<n>` (AArch64)
+//! * `rvv_reg`: a vector register (RISC-V). `core::arch` doesn't have any types for these yet, so the type isn't checked.
+//!
+//! A variable that's bound to an explicit vector register, like `"{ymm2}"`, is checked the same way if it has an explicit
+//! type. Vector registers overlap like general-purpose ones do, so `"{xmm3}"` and `"{ymm3}"` can't both be inputs.
+//!
+//! A slice can be passed to the ASM code with a fourth keyword, `slice_in`, which binds the slice's pointer and its length to two
+//! separate registers:
+//!
+//! ```text
+//! let [mut] <identifier>: [<type>:] slice_in(<ptr-constraint>, <len-constraint>) = <expression>;
+//! ```
+//!
+//! The ASM code refers to them as `$<identifier>.ptr` and `$<identifier>.len`. (Any type with `as_ptr` and `len` methods works.)
+//! If the slice is mutable (like `&mut data[..]`), its pointer comes from `as_mut_ptr` instead, and every `asm` block that uses
+//! it clobbers `"memory"`, as described in [The `asm` Block](#the-asm-block). A shared slice doesn't need that, since the ASM
+//! code should only read through it.
+//!
+//! An integer that's too wide for one register (like a `u64` on 32-bit x86, or a `u128` on x86-64) can be split across two registers
+//! by giving `in`, `out`, or `inout` two constraints, one for each half:
+//!
+//! ```text
+//! let [mut] <identifier>: <type>: in(<lo-constraint>, <hi-constraint>) [= <expression>];
+//! ```
+//!
+//! The type has to be given explicitly, and it has to be `u64`, `i64`, `u128`, or `i128`. The ASM code refers to the halves as
+//! `$<identifier>.lo` and `$<identifier>.hi`. An output is put back together from its halves after each `asm` block.
+//!
+//! A `bool` output can be set from one of the processor's condition flags, like the zero flag on x86, by giving `out` a
+//! condition code instead of a constraint:
+//!
+//! ```text
+//! let <identifier>[: bool]: out(flag <condition>);
+//! ```
+//!
+//! The condition code is a string that the target understands, like `"z"` or `"nc"` on x86 and x86-64, or `"eq"` or `"hs"`
+//! on AArch64. Other targets don't support flag outputs. The flag is read after the ASM code runs, so the ASM code can't
+//! refer to the variable. By default, an extra instruction (`setcc` on x86 or `cset` on AArch64) copies the flag to a
+//! register; with the `flag-outputs` feature, the compiler reads the flag itself.
+//!
 //! In addition, you can specify that you'll clobber a particular register (or that you'll clobber memory) with this syntax:
 //!
 //! ```text
@@ -143,6 +243,22 @@
 //!
 //! where `<constraint>` is either the name of a register (like `"eax"`) or `"memory"`.
 //!
+//! No two inputs can be bound to the same register, and neither can two outputs. On x86, the macro knows which names refer to parts
+//! of the same register (like `al`, `ah`, `ax`, `eax`, and `rax`), so `in("{al}")` and `in("{eax}")` conflict, but `in("{al}")` and
+//! `in("{ah}")` don't. A clobber that overlaps a bridge variable's register is handled automatically if the variable covers the
+//! whole clobbered register: it's removed in favor of an output, or an input is turned into an input and output pair. If the clobber
+//! covers more than that (like `clobber("rax")` with `out("{eax}")`), it's an error.
+//!
+//! Some registers can't be used in constraints or clobbers at all, because LLVM or the platform needs them to keep their values.
+//! Using one of them is an error when compiling for that target:
+//!
+//! * x86-64: `rsp`, `rbp`, and `rbx` (under any of their names, like `esp` or `bl`)
+//! * x86: `esp`, `ebp`, and `esi`
+//! * AArch64: `xzr`, `sp`, `x18`, `x19`, and `x29`
+//! * RISC-V: `zero`, `sp`, `gp`, `tp`, `s0`, and `s1`
+//!
+//! To use one of these registers anyway, save its value and restore it in the ASM code.
+//!
 //! These statements correspond to LLVM constraints in the following way:
 //!
 //! ```text
@@ -167,7 +283,7 @@
 //! been created thus far. The `asm` block's syntax is as follows:
 //!
 //! ```text
-//! asm [(<options>)] {
+//! asm [(<options>)] [-> <outputs>] {
 //!     "<asm-code>"
 //! }
 //! ```
@@ -176,9 +292,219 @@
 //! as `"volatile"`. `<asm-code>` is pure ASM code, enclosed in quotes, except that it can (and should) use the bridge variables
 //! that have been defined above the `asm` block.
 //!
+//! If an input that the `asm` block uses might point to mutable memory, the block also clobbers `"memory"`, so the compiler doesn't
+//! assume the memory is unchanged. An input counts as a pointer to mutable memory if its type is `*mut T` or `&mut T`, or (if it has
+//! no explicit type) if its initializer is something like `&mut x`, `x as *mut T`, or `x.as_mut_ptr()`. To skip the clobber, add one
+//! of these options to the `asm` block:
+//!
+//! * `readonly`: the ASM code may read memory, but it never writes to it.
+//! * `nomem`: the ASM code doesn't access memory at all. It can't be combined with `clobber("memory")`.
+//!
+//! With the `checked` option, a debug build makes sure that the ASM code doesn't change any general-purpose register that it hasn't
+//! declared as an output or a clobber. A missing clobber is easy to overlook, since it usually only causes trouble after the code
+//! is inlined somewhere new. The ASM code is surrounded with code that saves every register, including the stack pointer, before
+//! and after it runs, and the code panics with the name of any register that changed without being declared. (Changing an input
+//! counts, too, unless it's tied to an output.) Release builds aren't affected. For now, this only works on x86 and x86-64; on
+//! other targets, the option does nothing. The saved values are written through a pointer that's kept on the stack, so the ASM code
+//! can use every register, as long as the stack pointer is back where it started by the time it finishes.
+//!
+//! With the `trace` option, a debug build logs the value of every input before the ASM code runs and every output after it,
+//! labeled with the bridge variable's identifier and the location of the `asm` block. Outputs that the block yields without
+//! naming them are labeled `yield` (or `yield.0`, `yield.1`, and so on for a tuple). By default, each value is printed with
+//! `std::eprintln!`, so it needs to implement `Debug`, and the crate needs `std`. To send the values somewhere else (like the
+//! `log` crate, or anywhere in `no_std` code), give a function to call instead, as in `asm(trace = my_hook)`:
+//!
+//! ```ignore
+//! fn my_hook(location: &str, direction: &str, name: &str, value: &dyn core::fmt::Debug) {
+//!     // `direction` is `"in"` or `"out"`.
+//!     log::trace!("[{}] {} {} = {:?}", location, direction, name, value);
+//! }
+//! ```
+//!
+//! With the `noreturn` option, the ASM code is promised never to finish, like a context switch or a jump to user space. The block
+//! becomes an expression of type `!`, so Rust code after it is known to be unreachable, and it can end a function that returns `!`
+//! without an `unreachable!()`. Such a block can't declare or yield any outputs, and no output bridge variable can be in its scope,
+//! since every one of them is an output of every `asm` block in its scope. If it has alternatives, the `else` and `fallback` blocks
+//! have to diverge too for the chain to have type `!`.
+//!
+//! ```ignore
+//! fn halt() -> ! {
+//!     unsafe {
+//!         rusty_asm! {
+//!             asm("volatile", noreturn) {
+//!                 "cli\n1:\n\thlt\n\tjmp 1b"
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+//!
 //! In order to reference a bridge variable from inside an `asm` block, insert `$<ident>` into the code, where `<ident>` is the
 //! variable's identifier. As with the `asm!` macro, `$$` encodes a literal dollar sign.
 //!
+//! For a value that's only needed once, the ASM code can also include a Rust expression directly, as `${<expr>}`. The expression
+//! becomes an input of its own, in a general-purpose register, and it's evaluated just before the ASM code runs. To put it in another
+//! register class, add the class's name after a colon, as in `${<expr>:xmm_reg}`:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let mut sum: inout("r") = a;
+//!     asm {
+//!         "addl ${b + 1}, $sum"
+//!     }
+//!     sum
+//! }
+//! ```
+//!
+//! (Anything like `${0:q}` or `${:uid}`, which starts with a digit or a colon, is left for LLVM.)
+//!
+//! The operands in the generated `asm!` invocation always come in the same order, so expanding the same code twice gives the same
+//! result:
+//!
+//! * Outputs are listed in the order their bridge variables were declared. Redeclaring an output (shadowing it) keeps its original
+//!   position, unless the new declaration has a `#[cfg]` attribute, in which case it goes at the end.
+//! * Inputs are listed in the order their bridge variables were declared, with the same rule for redeclarations. An `inout` variable
+//!   contributes its output in the output list and its tied input in the input list.
+//! * Operands that are declared in an `asm` block's header, followed by any outputs that it yields, are treated as if they were
+//!   declared just before the block.
+//! * If the block has any labels, the output that says which one was taken comes next.
+//! * Inputs that are given as Rust expressions come after the other inputs, in the order they appear in the ASM code.
+//! * Clobbers are listed in the order they were declared. A repeated clobber keeps the position of its first declaration.
+//!
+//! Any adjustments the macro makes (such as turning a clobber that overlaps an input into an output) add the new operands after
+//! the ones listed above, without moving any existing operand.
+//!
+//! An `asm` block can have alternatives for other targets, chained with `else`. Each block in the chain needs the
+//! `target_arch = "<arch>"` option, and only the block whose target matches the one being compiled for is used. The chain can
+//! end with a block of ordinary Rust code, which is used on every other target:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let x: in("r") = x;
+//!     let y: u32: out("r");
+//!     asm(target_arch = "x86_64", "intel") {
+//!         "lea $y, [$x + 1]"
+//!     } else asm(target_arch = "aarch64") {
+//!         "add $y, $x, #1"
+//!     } else {
+//!         y = x + 1;
+//!     }
+//!     y
+//! }
+//! ```
+//!
+//! Every alternative sees the same bridge variables, so they all have the same outputs. The Rust code has to assign every output
+//! that the `asm` blocks use. Without an `else` block, compiling for a target that has no alternative is an error.
+//!
+//! An `asm` block (or a chain of them) can also end with a `fallback` block of Rust code, which computes the same outputs without
+//! any ASM code. It's used instead of the ASM code when the program runs under Miri, on targets that Rust doesn't support inline ASM
+//! on (like WebAssembly), and everywhere if the `force-fallback` feature is enabled:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let mut x: inout("r") = x;
+//!     asm {
+//!         "bswap $x"
+//!     } fallback {
+//!         x = x.swap_bytes();
+//!     }
+//!     x
+//! }
+//! ```
+//!
+//! Like an `else` block, a `fallback` block sees the same bridge variables as the ASM code, and it has to assign every output that
+//! the `asm` blocks use.
+//!
+//! An `asm` block can also be used as an expression, which evaluates to the outputs that are declared after `->` in its header.
+//! Those outputs don't need bridge variables:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let (lo, hi): (u32, u32) = asm("volatile") -> (out("{eax}"), out("{edx}")) {
+//!         "rdtsc"
+//!     };
+//!     (u64::from(hi) << 32) | u64::from(lo)
+//! }
+//! ```
+//!
+//! Each output is written as `out(<constraint>) [<ident>] [: <type> [as <register-type>]]`, with the same constraints as a bridge
+//! variable. It only needs a name if the ASM code refers to it, and that name hides any bridge variable with the same name in
+//! that block. A single output is yielded as it is, and a parenthesized list of them as a tuple. If the block has alternatives,
+//! each one declares its own outputs with the same shape, and the `else` and `fallback` blocks evaluate to the same value.
+//!
+//! The macro only looks for `asm` blocks at the statement level, so an `asm` expression has to be a whole statement, the value of
+//! a `let` statement, or the value at the end of a block. One inside parentheses, brackets, or an `if` condition (as an argument
+//! to a function, for example) is an error. Its value can be assigned to a variable first instead:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let ticks: u32 = asm("volatile") -> out("{eax}") { "rdtsc" };
+//!     report(ticks);
+//! }
+//! ```
+//!
+//! Operands can also be declared among the options in an `asm` block's header, as
+//! `<constraint> [mut] <ident> [: <type> [as <register-type>]] [= <expr>]`. Unlike a bridge variable, such an operand is only in
+//! scope in that block, so later blocks don't see it (or warn that they don't use it). An input needs a value, and an `inout`
+//! operand is mutable even without `mut`:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let mut total: inout("r") = total;
+//!     asm(in("r") x = a + b, out("r") scratch, "intel") {
+//!         "lea $scratch, [$x + $x]\n\tadd $total, $scratch"
+//!     }
+//!     total
+//! }
+//! ```
+//!
+//! The ASM code can also choose which Rust code runs after it. Each label is written after the block as `<label> => { ... }`, and
+//! the ASM code refers to it as `$<label>`:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let x: in("r") = x;
+//!     asm {
+//!         "test $x, $x\n\tjz $on_zero"
+//!     } on_zero => {
+//!         return None;
+//!     }
+//!     Some(100 / x)
+//! }
+//! ```
+//!
+//! The label's code runs after the `asm` block, with every output already set. This isn't a real jump, since `asm!` can't branch
+//! out of the ASM code. Instead, `$<label>` is a stub at the end of the ASM code that stores the label's number in an extra output
+//! and jumps past the other stubs, and the generated Rust code then branches on that output. So taking a label costs an extra store
+//! and branch compared to a jump straight into the Rust code, and the extra output ties up a register. This works on x86, x86-64,
+//! ARM, AArch64, and RISC-V. Each alternative in a chain has its own labels, and a `noreturn` block can't have any.
+//!
+//! Bridge variable declarations, clobbers, and `asm` blocks can have `#[cfg]` and `#[cfg_attr]` attributes, just like Rust
+//! statements. Since the macro can't tell which configuration is being compiled, it generates a separate `asm!` invocation for
+//! each group of configurations that gives a block the same operands and clobbers, so the numbering is always right:
+//!
+//! ```ignore
+//! rusty_asm! {
+//!     let mut x: inout("r") = x;
+//!     #[cfg(feature = "offset")]
+//!     let offset: in("r") = OFFSET;
+//!     #[cfg(feature = "offset")]
+//!     asm("intel") {
+//!         "add $x, $offset"
+//!     }
+//!     #[cfg(target_os = "windows")]
+//!     clobber("rdx");
+//!     asm("intel") {
+//!         "imul $x, $x"
+//!     }
+//!     x
+//! }
+//! ```
+//!
+//! One block can depend on at most 10 different `#[cfg]` predicates. Other attributes on a bridge variable or an `asm` block are
+//! passed on to the generated code, but a clobber can only have `#[cfg]` and `#[cfg_attr]`, and an `asm` block that yields outputs
+//! can't have any attributes.
+//!
 //! ## The `rusty_asm!` Block and Scope
 //!
 //! The new macro puts its entire contents inside a new scope, so that any variables defined therein are dropped at the end. Their
@@ -224,6 +550,46 @@
 //! Defining bridge variables in `if let` and `while let` constructions is still not supported, since Rust doesn't support explicit
 //! type annotations in them either, and I imagine the syntax would become overly complex.
 //!
+//! ## Lints
+//!
+//! Each of the warnings that `rusty_asm!` produces belongs to a named lint:
+//!
+//! * `unused_bridge_var`: a bridge variable isn't used by any `asm` block in its scope. Binding it to an explicit register (like
+//!   `"{eax}"`) counts as using it.
+//! * `unread_output`: an `asm` block writes to an output, but neither Rust code nor a later `asm` block ever reads the value.
+//!   If you just need to reserve the register, use `clobber` instead.
+//! * `clobber_overlap`: an output's register covers a clobber, so the clobber is ignored.
+//! * `stray_dollar`: a `$` in the ASM code isn't followed by an identifier or another `$`.
+//! * `unrecognized_bridge_var`: a `$<ident>` in the ASM code doesn't name a bridge variable.
+//! * `implicit_memory_clobber`: an `asm` block clobbers `"memory"` because an input might point to mutable memory, but it
+//!   doesn't say so explicitly.
+//!
+//! The `rusty_asm` lint group contains all of them. Their levels can be set with `allow`, `warn`, and `deny`, just like Rust's
+//! lints. A denied lint is a compile error. To set the levels for a whole block (including the whole `rusty_asm!` invocation) and
+//! any blocks inside it, use an inner attribute at the start of the block:
+//!
+//! ```text
+//! rusty_asm! {
+//!     #![deny(rusty_asm)]
+//!     #![allow(unused_bridge_var)]
+//!     /* ... */
+//! }
+//! ```
+//!
+//! To set them for a single `asm` block, add them to its options:
+//!
+//! ```text
+//! asm("volatile", allow(stray_dollar)) {
+//!     /* ... */
+//! }
+//! ```
+//!
+//! `unused_bridge_var` and `unread_output` are checked where the bridge variable is declared, so they use the levels of the
+//! block that contains the declaration, not those of any `asm` block. Bridge variables whose names start with `_` are never
+//! reported as unused or unread.
+//!
+//! Lints from Rust can be mixed into the same attributes. `rusty_asm!` only removes the ones it knows about.
+//!
 //! ## Further Reading
 //!
 //! There are too many platform-specific constraints and options that you can specify to list them all here. Follow these links for
@@ -305,7 +671,7 @@
 //! }
 //! ```
 
-#![cfg_attr(all(feature = "proc-macro"), feature(proc_macro_diagnostic))]
+#![cfg_attr(all(feature = "proc-macro"), feature(proc_macro_diagnostic, proc_macro_span))]
 #![recursion_limit = "128"]
 
 extern crate proc_macro;
@@ -316,7 +682,11 @@ extern crate syn;
 
 use proc_macro2::TokenStream;
 
+mod cfg;
+mod derive;
+mod diagnostics;
 mod parse;
+mod registers;
 use self::parse::RustyAsmBlock;
 
 /// Allows bridge variables, clobbers, and `asm` blocks to be defined.
@@ -329,9 +699,29 @@ pub fn rusty_asm(ts: proc_macro::TokenStream) -> proc_macro::TokenStream {
     rusty_asm_internal(ts.into()).into()
 }
 
+/// Implements `rusty_asm_runtime::AsmOperand` for a `#[repr(transparent)]` struct or a fieldless enum with an integer
+/// representation, like `#[repr(u8)]`.
+///
+/// This is re-exported by the `rusty-asm-runtime` crate, which the generated code refers to.
+#[proc_macro_derive(AsmOperand)]
+pub fn derive_asm_operand(ts: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    syn::parse2::<syn::DeriveInput>(ts.into())
+        .and_then(derive::derive_asm_operand)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
 fn rusty_asm_internal(ts: TokenStream) -> TokenStream {
     match syn::parse2::<RustyAsmBlock>(ts) {
-        Ok(rusty_block) => quote!(#rusty_block),
+        Ok(rusty_block) => {
+            // The parser recovers from most errors, so the block can contain some `compile_error!`s along with the
+            // code that was parsed successfully.
+            if cfg!(test) && !rusty_block.errors().is_empty() {
+                let messages = rusty_block.errors().iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                panic!("invalid rusty_asm! block: {}", messages.join("; "));
+            }
+            quote!(#rusty_block)
+        },
         Err(e) => {
             // The test harness relies on panics, but producing a `compile_error!` gives better
             // error messages.
@@ -348,10 +738,816 @@ fn rusty_asm_internal(ts: TokenStream) -> TokenStream {
 mod tests {
     extern crate runtime_macros;
     use self::runtime_macros::emulate_macro_expansion_fallible;
-    use crate::rusty_asm_internal;
+    use crate::{rusty_asm_internal, RustyAsmBlock};
+    use crate::diagnostics::{excerpt, help_suffix, source_range};
+    use proc_macro2::TokenStream;
     use std::{env, fs};
     use std::path::PathBuf;
 
+    fn expand(source: &str) -> String {
+        rusty_asm_internal(source.parse::<TokenStream>().unwrap()).to_string()
+    }
+
+    // Expands the block even if it has errors, and returns the expansion along with the errors' messages.
+    fn expand_with_errors(source: &str) -> (String, Vec<String>) {
+        let block = syn::parse2::<RustyAsmBlock>(source.parse().unwrap()).unwrap();
+        (quote!(#block).to_string(), block.errors().iter().map(|e| e.to_string()).collect())
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        expand_with_errors(source).1
+    }
+
+    #[test]
+    fn operand_ordering() {
+        let expanded = expand(r#"
+            let a: out("r");
+            clobber("memory");
+            let b: in("r") = 1;
+            clobber("cc");
+            let mut c: inout("r") = 2;
+            clobber("memory");
+            asm { "$a $b $c" }
+        "#);
+        assert!(expanded.contains(
+            r#"asm ! ( "$0 $2 $1" : "=r" ( a ) , "=r" ( c ) : "r" ( b ) , "1" ( c ) : "memory" , "cc" : )"#
+        ));
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let (expanded, messages) = expand_with_errors(r#"
+            let a: in("r") = 1;
+            let b: in(r) = 2;
+            clobber("memory" "cc");
+            {
+                let c: out("r") junk;
+                asm { "$a" 3 }
+                let d: u8: in("r") = 4;
+            }
+            asm("volatile") { "$a" }
+        "#);
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+
+        // Everything else is still expanded, with invalid bridge variables as ordinary variables and invalid `asm`
+        // blocks removed.
+        assert!(expanded.contains("let a = 1 ;"));
+        assert!(expanded.contains("let b = 2 ;"));
+        assert!(expanded.contains("{ { let c ; let d : u8 = 4 ; } }"));
+        assert!(expanded.contains(r#"asm ! ( "$0" : : "r" ( a ) : : "volatile" )"#));
+    }
+
+    #[test]
+    fn lint_levels() {
+        let (expanded, messages) = expand_with_errors(r#"
+            #![deny(rusty_asm)]
+            #![allow(unused_variables, stray_dollar)]
+            let a: in("r") = 1;
+            asm { "$ $a" }
+            let b: in("r") = 2;
+            {
+                #![allow(unused_bridge_var)]
+                let c: in("r") = 3;
+                asm { "$a" }
+            }
+            asm(deny(stray_dollar), "volatile") { "$ $a" }
+        "#);
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(messages[0].starts_with("expected an identifier after `$`"));
+        assert!(messages[1].starts_with("bridge variable not used"));
+        assert!(messages[1].ends_with("`#[deny(unused_bridge_var)]` is in effect"));
+
+        // Only the lints that Rust knows about are left for Rust to see, ahead of the errors, since inner attributes
+        // have to come first.
+        assert!(expanded.starts_with("{ # ! [ allow ( unused_variables ) ] compile_error !"), "{}", expanded);
+        assert!(expanded.contains(r#": : "volatile" )"#));
+    }
+
+    #[test]
+    fn bridge_var_usage() {
+        let messages = errors(r#"
+            #![deny(unused_bridge_var, unread_output)]
+            let a: in("r") = 1;
+            let b: out("r");
+            let c: out("r");
+            let _d: in("r") = 4;
+            let port: in("{dx}") = 5;
+            {
+                asm { "$a $b" }
+            }
+            let a: in("r") = 2;
+            let mut e: inout("r") = 0;
+            loop {
+                if e > 10 { break; }
+                asm { "$e $c" }
+            }
+            let f: out("r");
+            let g: out("r");
+            asm { "$f $g" }
+            let f = 1;
+            let g = g + 1;
+            let h: out("r");
+            let i: out("r");
+            let k: out("r");
+            let m: out("r");
+            drop(h);
+            asm { "$h $i $k" }
+            let (i, j) = (1, 2);
+            let Point { k, .. } = point;
+            loop {
+                drop(m);
+                asm { "$m" }
+            }
+            b + f + g + i + j + k
+        "#);
+        assert_eq!(messages.len(), 6, "{:?}", messages);
+        assert!(messages[0].starts_with("value written to output is never read")); // `c`
+        assert!(messages[1].starts_with("bridge variable not used")); // The second `a`
+        // `f`, `i`, and `k` are shadowed by Rust `let` statements, and `h` is only read before it's written.
+        for message in messages[2 ..].iter() {
+            assert!(message.starts_with("value written to output is never read"), "{:?}", messages);
+        }
+    }
+
+    #[test]
+    fn register_overlaps() {
+        // Clobbers that a bridge variable fully covers are fixed automatically.
+        let expanded = expand(r#"
+            let a: out("{eax}");
+            clobber("ax");
+            let b: in("{ecx}") = 1;
+            clobber("cl");
+            asm { "" }
+            a
+        "#);
+        assert!(expanded.contains(r#"asm ! ( "" : "={eax}" ( a ) , "={ecx}" ( b ) : "1" ( b ) : : )"#), "{}", expanded);
+
+        // Everything else is an error.
+        let messages = errors(r#"
+            let a: out("{eax}");
+            let b: out("{al}");
+            let c: in("{dx}") = 1;
+            let d: inout("{rdx}") = 2;
+            let e: in("{cl}") = 3;
+            let f: in("{ch}") = 4;
+            clobber("rcx");
+            asm { "" }
+            let d: in("{r8d}") = 5;
+        "#);
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        assert!(messages[0].starts_with("register `al` overlaps register `eax`, which is already bound to output `a`"));
+        assert!(messages[1].starts_with("register `rdx` overlaps register `dx`, which is already bound to input `c`"));
+        assert!(messages[2].starts_with("clobber `rcx` overlaps input `e` (`cl`)"));
+        assert!(messages[3].starts_with("clobber `rcx` overlaps input `f` (`ch`)"));
+    }
+
+    #[test]
+    fn reserved_registers() {
+        let expanded = expand(r#"
+            let a: in("{ebx}") = 1;
+            clobber("sp");
+            let b: in("{x3}") = 2;
+            asm { "$a $b" }
+        "#);
+        assert_eq!(expanded.matches("compile_error").count(), 1 + 5 + 2, "{}", expanded);
+        assert!(expanded.contains(
+            r#"# [ cfg ( target_arch = "x86_64" ) ] compile_error ! ( "register `ebx` can't be used on x86_64, since LLVM uses it internally"#
+        ));
+        assert!(expanded.contains(r#"# [ cfg ( target_arch = "riscv64" ) ] compile_error ! ( "register `x3` can't be used on riscv64"#));
+
+        let expanded = expand(r#"
+            let a: in("{x19}") = 1;
+            clobber("s0");
+            let b: in("{wzr}") = 0;
+            asm { "$a $b" }
+        "#);
+        assert_eq!(expanded.matches("compile_error").count(), 1 + 2 + 1, "{}", expanded);
+        assert!(expanded.contains(
+            r#"# [ cfg ( target_arch = "aarch64" ) ] compile_error ! ( "register `wzr` can't be used on aarch64, since it's always zero"#
+        ));
+        assert!(expanded.contains(
+            r#"# [ cfg ( target_arch = "aarch64" ) ] compile_error ! ( "register `x19` can't be used on aarch64, since LLVM uses it internally"#
+        ));
+        assert!(expanded.contains(
+            r#"# [ cfg ( target_arch = "riscv32" ) ] compile_error ! ( "register `s0` can't be used on riscv32, since it's the frame pointer"#
+        ));
+    }
+
+    #[test]
+    fn memory_clobbers() {
+        let expanded = expand(r#"
+            #![allow(implicit_memory_clobber)]
+            let a: in("r") = &mut x;
+            let b: *const u8: in("r") = x.as_mut_ptr();
+            asm { "$a $b" }
+            asm(readonly) { "$a" }
+            asm { "$b" }
+            let c: in("r") = buf.as_mut_ptr();
+            clobber("cc");
+            asm { "$c" }
+        "#);
+        assert!(expanded.contains(r#"asm ! ( "$0 $1" : : "r" ( a ) , "r" ( b ) : "memory" : )"#), "{}", expanded);
+        assert!(expanded.contains(r#"asm ! ( "$0" : : "r" ( a ) , "r" ( b ) : : )"#));
+        assert!(expanded.contains(r#"asm ! ( "$1" : : "r" ( a ) , "r" ( b ) : : )"#));
+        assert!(expanded.contains(r#"asm ! ( "$2" : : "r" ( a ) , "r" ( b ) , "r" ( c ) : "cc" , "memory" : )"#));
+
+        let messages = errors(r#"
+            clobber("memory");
+            asm(nomem) { "" }
+            asm(nomem, readonly) { "" }
+        "#);
+        assert_eq!(messages, [
+            "`nomem` can't be used along with `clobber(\"memory\")`",
+            "`readonly` can't be used together with `nomem`"
+        ]);
+    }
+
+    #[test]
+    fn slices() {
+        let expanded = expand(r#"
+            #![allow(implicit_memory_clobber)]
+            let a: out("r");
+            let buf: slice_in("r", "{rcx}") = &data[..];
+            let dst: slice_in("r", "r") = &mut out[..];
+            asm { "$a $buf.ptr $buf.len $dst.ptr $dst.len" }
+            let buf: in("r") = 5;
+            asm(nomem) { "$a $buf" }
+            let mut x: inout("r") = 1;
+            let y: inout("r") = 2;
+            let x: slice_in("r", "r") = &v[..];
+            asm(nomem) { "$x.ptr $y" }
+            a
+        "#);
+        assert!(expanded.contains(concat!(
+            r#"asm ! ( "$0 $1 $2 $3 $4" : "=r" ( a ) : "r" ( buf . as_ptr ( ) ) , "{rcx}" ( buf . len ( ) ) , "#,
+            r#""r" ( dst . as_mut_ptr ( ) ) , "r" ( dst . len ( ) ) : "memory" : )"#
+        )), "{}", expanded);
+        assert!(expanded.contains(
+            r#"asm ! ( "$0 $3" : "=r" ( a ) : "r" ( dst . as_mut_ptr ( ) ) , "r" ( dst . len ( ) ) , "r" ( buf ) : : )"#
+        ));
+        assert!(expanded.contains(concat!(
+            r#"asm ! ( "$6 $1" : "=r" ( a ) , "=r" ( y ) : "r" ( dst . as_mut_ptr ( ) ) , "r" ( dst . len ( ) ) , "#,
+            r#""r" ( buf ) , "1" ( y ) , "r" ( x . as_ptr ( ) ) , "r" ( x . len ( ) ) : : )"#
+        )), "{}", expanded);
+
+        // A mutable slice's pointer isn't reborrowed as shared, since the ASM code may write through it.
+        let expanded = expand(r#"
+            let dst: &mut [u8]: slice_in("r", "r") = out;
+            asm { "rep stosb ($dst.ptr), $dst.len" }
+        "#);
+        assert!(expanded.contains(r#""r" ( dst . as_mut_ptr ( ) ) , "r" ( dst . len ( ) ) : "memory""#), "{}", expanded);
+
+        let messages = errors(r#"
+            #![deny(unrecognized_bridge_var)]
+            let buf: slice_in("r", "r") = &v[..];
+            let len: slice_in("r", "r");
+            asm { "$buf.len $buf.lenx" }
+        "#);
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(messages[0].starts_with("expected `=`; a slice bridge variable needs an initializer"));
+        assert!(messages[1].starts_with("bridge variable `buf` used without `.ptr` or `.len`"));
+    }
+
+    #[test]
+    fn wide_values() {
+        let expanded = expand(r#"
+            let mut a: u128: inout("r", "r") = 1;
+            let b: i64: in("{eax}", "{edx}") = -2;
+            asm { "$a.lo $a.hi $b.lo $b.hi" }
+            a
+        "#);
+        assert!(expanded.contains(concat!(
+            "{ let __rusty_asm_a_lo : u64 ; let __rusty_asm_a_hi : u64 ; ",
+            r#"asm ! ( "$0 $1 $4 $5" : "=r" ( __rusty_asm_a_lo ) , "=r" ( __rusty_asm_a_hi ) : "#,
+            r#""0" ( a as u64 ) , "1" ( ( ( a as u128 ) >> 64 ) as u64 ) , "#,
+            r#""{eax}" ( b as u32 ) , "{edx}" ( ( ( b as u64 ) >> 32 ) as u32 ) : : ) ; "#,
+            "a = ( ( ( __rusty_asm_a_hi as u128 ) << 64 ) | ( __rusty_asm_a_lo as u128 ) ) as u128 ; }"
+        )), "{}", expanded);
+
+        let messages = errors(r#"
+            let c: u32: in("r", "r") = 1;
+            let d: out("r", "r");
+        "#);
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(messages.iter().all(|message| message.starts_with(
+            "a bridge variable that's split across two registers needs an explicit type"
+        )));
+    }
+
+    #[test]
+    fn flag_outputs() {
+        let expanded = expand(r#"
+            let zero: out(flag "z");
+            let x: in("r") = 1u32;
+            asm { "test $x, $x" }
+            zero
+        "#);
+        assert!(expanded.contains(r#"compile_error ! ( "condition code `z` doesn't exist on aarch64" ) ;"#),
+            "{}", expanded);
+        assert!(expanded.contains("let zero : bool ;"), "{}", expanded);
+        if cfg!(feature = "flag-outputs") {
+            assert!(expanded.contains(r#"asm ! ( "test $1, $1" : "=@ccz" ( zero ) : "r" ( x ) : : ) ;"#), "{}", expanded);
+        } else {
+            assert!(expanded.contains(concat!(
+                "{ let __rusty_asm_zero : u8 ; ",
+                r#"# [ cfg ( any ( target_arch = "x86" , target_arch = "x86_64" ) ) ] "#,
+                r#"asm ! ( "test $1, $1\n\tsetz $0" : "=q" ( __rusty_asm_zero ) : "r" ( x ) : : ) ; "#,
+                "zero = __rusty_asm_zero != 0 ; }"
+            )), "{}", expanded);
+        }
+
+        let messages = errors(r#"
+            let a: bool: inout(flag "z") = false;
+            let b: u8: out(flag "z");
+            let c: out(flag "zz");
+            let d: out(flag "eq");
+            asm { "$d" }
+        "#);
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        assert_eq!(messages[.. 3], [
+            "a flag can only be bound with `out`",
+            "a flag output has to be a `bool`",
+            "unknown condition code `zz`"
+        ]);
+        assert!(messages[3].starts_with("flag output `d` can't be referenced in ASM code"), "{}", messages[3]);
+    }
+
+    #[test]
+    fn register_classes() {
+        let expanded = expand(r#"
+            let a: __m128: in(xmm_reg) = x;
+            let c: core::arch::x86_64::__m512i: out("{zmm1}");
+            asm { "$a $c" }
+            c
+        "#);
+        assert!(expanded.contains(r#"compile_error ! ( "register class `xmm_reg` only exists on x86 or x86_64" ) ;"#),
+            "{}", expanded);
+        assert!(expanded.contains(r#"asm ! ( "$1 $0" : "={zmm1}" ( c ) : "x" ( a ) : : )"#), "{}", expanded);
+
+        let expanded = expand(r#"
+            let mut b: uint8x16_t: inout(qreg) = y;
+            asm { "$b" }
+            b
+        "#);
+        assert!(expanded.contains(r#"asm ! ( "${0:q}" : "=w" ( b ) : "0" ( b ) : : )"#), "{}", expanded);
+
+        let messages = errors(r#"
+            let d: __m256: in(xmm_reg) = 1;
+            let e: in(ymm_reg) = 2;
+            let f: u32: in(foo) = 3;
+            let g: __m128: in("{ymm2}") = 4;
+            let h: u64: in(xmm_reg, xmm_reg) = 5;
+            let i: __m128: in("{xmm3}") = 6;
+            let j: __m256: in("{ymm3}") = 7;
+            clobber("zmm4");
+            let k: __m128: in("{xmm4}") = 8;
+            asm { "" }
+        "#);
+        let xmm_types = help_suffix("use one of `__m128`, `__m128i`, `__m128d`");
+        let ymm_types = help_suffix("use one of `__m256`, `__m256i`, `__m256d`");
+        assert_eq!(messages, [
+            format!("type `__m256` doesn't fit in `xmm_reg`{}", xmm_types).as_str(),
+            format!("a bridge variable in `ymm_reg` needs an explicit type{}", ymm_types).as_str(),
+            "unknown register class `foo`",
+            format!("type `__m128` doesn't fit in register `ymm2`{}", ymm_types).as_str(),
+            "a register class can only be used for a bridge variable that fits in one register",
+            "register `ymm3` overlaps register `xmm3`, which is already bound to input `i`",
+            "clobber `zmm4` overlaps input `k` (`xmm4`), but the input doesn't cover the whole clobbered register; bind \
+            `k` to `{zmm4}` instead"
+        ]);
+    }
+
+    #[test]
+    fn conversions() {
+        let expanded = expand(r#"
+            let mut ready: bool as u32: inout("r") = true;
+            let p: NonNull<u8> as usize: in("r") = ptr;
+            let kind: Kind as u8: out("r");
+            asm { "$ready $p $kind" }
+            (ready, kind)
+        "#);
+        assert!(expanded.contains("let mut ready : bool = true ;"), "{}", expanded);
+        assert!(expanded.contains(concat!(
+            "{ let __rusty_asm_ready : u32 ; let __rusty_asm_kind : u8 ; ",
+            r#"asm ! ( "$0 $3 $1" : "=r" ( __rusty_asm_ready ) , "=r" ( __rusty_asm_kind ) : "#,
+            r#""0" ( ready as u32 ) , "#,
+            r#""r" ( :: core :: mem :: transmute :: < NonNull < u8 > , usize > ( p ) ) : : ) ; "#,
+            "ready = __rusty_asm_ready != 0 ; ",
+            "kind = :: core :: mem :: transmute :: < u8 , Kind > ( __rusty_asm_kind ) ; }"
+        )), "{}", expanded);
+
+        let messages = errors(r#"
+            let a: bool as u8: out(flag "z");
+            let b: u128 as u128: in("r", "r") = 1;
+            let c: Mask as __m256: in(xmm_reg) = m;
+        "#);
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert_eq!(messages[.. 2], [
+            "a bridge variable can only be converted if it fits in one register",
+            "a bridge variable can only be converted if it fits in one register"
+        ]);
+        assert!(messages[2].starts_with("type `__m256` doesn't fit in `xmm_reg`"), "{:?}", messages);
+    }
+
+    #[test]
+    fn operand_trait() {
+        let expanded = expand(r#"
+            let h: Handle as _: in(reg) = handle;
+            let mut v: Vector as _: inout("{xmm1}") = v;
+            asm { "$h $v" }
+            v
+        "#);
+        assert!(expanded.contains("let _ = :: rusty_asm_runtime :: ClassCheck :: < Handle > :: REG ; let h : Handle"),
+            "{}", expanded);
+        assert!(expanded.contains("let _ = :: rusty_asm_runtime :: ClassCheck :: < Vector > :: XMM_REG ;"),
+            "{}", expanded);
+        assert!(expanded.contains(concat!(
+            "{ let __rusty_asm_v : < Vector as :: rusty_asm_runtime :: AsmOperand > :: Register ; ",
+            r#"asm ! ( "$1 $0" : "={xmm1}" ( __rusty_asm_v ) : "#,
+            r#""r" ( < Handle as :: rusty_asm_runtime :: AsmOperand > :: to_register ( h ) ) , "#,
+            r#""0" ( < Vector as :: rusty_asm_runtime :: AsmOperand > :: to_register ( v ) ) : : ) ; "#,
+            "v = < Vector as :: rusty_asm_runtime :: AsmOperand > :: from_register ( __rusty_asm_v ) ; }"
+        )), "{}", expanded);
+    }
+
+    #[test]
+    fn alternatives() {
+        let expanded = expand(r#"
+            let x: in("r") = x;
+            let y: u32: out("r");
+            asm(target_arch = "x86_64", "intel") {
+                "lea $y, [$x + 1]"
+            } else asm(target_arch = "aarch64") {
+                "add $y, $x, #1"
+            } else {
+                y = x + 1;
+            }
+            y
+        "#);
+        assert!(expanded.contains(concat!(
+            r#"# [ cfg ( target_arch = "x86_64" ) ] { asm ! ( "lea $0, [$1 + 1]" : "=r" ( y ) : "r" ( x ) : : "#,
+            r#""intel" ) ; } "#,
+            r#"# [ cfg ( target_arch = "aarch64" ) ] { asm ! ( "add $0, $1, #1" : "=r" ( y ) : "r" ( x ) : : ) ; } "#,
+            r#"# [ cfg ( not ( any ( target_arch = "x86_64" , target_arch = "aarch64" ) ) ) ] { y = x + 1 ; }"#
+        )), "{}", expanded);
+
+        let expanded = expand(r#"
+            let y: u32: out("r");
+            asm(target_arch = "x86") { "movl $$1, $y" }
+        "#);
+        assert!(expanded.contains(r#"# [ cfg ( not ( any ( target_arch = "x86" ) ) ) ] compile_error !"#),
+            "{}", expanded);
+
+        let messages = errors(r#"
+            let y: u32: out("r");
+            let z: u32: out("r");
+            asm(target_arch = "x86") { "$y $z" } else asm { "$y $z" } else { if z == 1 { y += 1; } }
+            asm(target_arch = "x86") { "$y" } else asm(target_arch = "x86") { "$y" } else { y = 0; }
+        "#);
+        assert_eq!(messages, [
+            format!("an `asm` block with an `else` branch needs a target{}",
+                help_suffix("add an option like `target_arch = \"x86_64\"`")).as_str(),
+            "the `else` block has to assign the same outputs as the `asm` blocks, but it doesn't assign `z`",
+            "more than one alternative is for `target_arch = \"x86\"`"
+        ]);
+    }
+
+    #[test]
+    fn fallbacks() {
+        let expanded = expand(r#"
+            let x: in("r") = x;
+            let y: u32: out("r");
+            asm { "bswap $y" } fallback { y = x.swap_bytes(); }
+            fallback(y)
+        "#);
+        if cfg!(feature = "force-fallback") {
+            assert!(expanded.contains("let y : u32 ; { y = x . swap_bytes ( ) ; } fallback ( y )"), "{}", expanded);
+        } else {
+            assert!(expanded.contains(r#"# [ cfg ( not ( any ( miri , not ( any ( target_arch = "x86" , "#),
+                "{}", expanded);
+            assert!(expanded.contains(concat!(
+                r#"target_arch = "msp430" ) ) ) ) ) ] { asm ! ( "bswap $0" : "=r" ( y ) : "r" ( x ) : : ) ; } "#,
+                r#"# [ cfg ( any ( miri , not ( any ( target_arch = "x86" , "#
+            )), "{}", expanded);
+            assert!(expanded.contains(r#"target_arch = "msp430" ) ) ) ) ] { y = x . swap_bytes ( ) ; } fallback ( y )"#),
+                "{}", expanded);
+        }
+
+        let messages = errors(r#"
+            let y: u32: out("r");
+            asm(target_arch = "x86") { "$y" } else { y = 1; } fallback { let _ = y; }
+        "#);
+        assert_eq!(messages, [
+            "the `fallback` block has to assign the same outputs as the `asm` blocks, but it doesn't assign `y`"
+        ]);
+    }
+
+    #[test]
+    fn checked() {
+        let expanded = expand(r#"
+            let x: in("r") = x;
+            let y: u32: out("r");
+            let z: u32: out("{edx}");
+            clobber("r12");
+            asm(checked) { "movl $x, $y" }
+        "#);
+        assert!(expanded.contains(concat!(
+            r#"{ # [ cfg ( not ( all ( debug_assertions , any ( target_arch = "x86_64" , target_arch = "x86" ) ) ) ) ] "#,
+            r#"asm ! ( "movl $2, $0" : "=r" ( y ) , "={edx}" ( z ) : "r" ( x ) : "r12" : ) ; "#,
+            r#"# [ cfg ( all ( debug_assertions , target_arch = "x86_64" ) ) ] { "#,
+            "let mut __rusty_asm_snapshots = [ 0usize ; 48usize ] ; ",
+            "let __rusty_asm_address = __rusty_asm_snapshots . as_mut_ptr ( ) ; ",
+            r#"asm ! ( "xchgq %rdi, $3\n\tmovq %rax, 0(%rdi)\n\tmovq %rbx, 8(%rdi)\n\t"#
+        )), "{}", expanded);
+        // The address register's own value is copied from the slot that held the address.
+        assert!(expanded.contains(concat!(
+            r#"\n\tmovq %r15, 120(%rdi)\n\tmovq $3, %rax\n\tmovq %rax, 40(%rdi)\n\tmovq 0(%rdi), %rax\n\t"#,
+            r#"xchgq %rdi, $3\n\tmovl $2, $0\n\txchgq %rdi, $3\n\tmovq %rax, 128(%rdi)\n\t"#
+        )), "{}", expanded);
+        assert!(expanded.contains(r#"\n\t.ifc ${0:q},%rbx\n\tmovq $$1, 264(%rdi)\n\t.endif\n\t"#), "{}", expanded);
+        assert!(expanded.contains(concat!(
+            r#"\n\t.endif\n\txchgq %rdi, $3" : "=r" ( y ) , "={edx}" ( z ) : "#,
+            r#""r" ( x ) , "m" ( __rusty_asm_address ) : "r12" , "memory" : ) ; "#,
+            r#"for & ( i , register ) in [ ( 0usize , "rax" ) , ( 1usize , "rbx" ) , ( 2usize , "rcx" ) , "#,
+            r#"( 4usize , "rsi" ) , ( 5usize , "rdi" ) , "#
+        )), "{}", expanded);
+        assert!(expanded.contains(r#"( 11usize , "r11" ) , ( 13usize , "r13" ) ,"#), "{}", expanded);
+        assert!(expanded.contains(r#"# [ cfg ( all ( debug_assertions , target_arch = "x86" ) ) ]"#), "{}", expanded);
+
+        // The address is kept in memory, so the ASM code can use `rdi` (`edi` on x86) like any other register.
+        let expanded = expand(r#"
+            let x: in("{edi}") = 1;
+            let y: u32: out("r");
+            asm(checked, "intel") { "mov $y, $x" }
+        "#);
+        assert!(expanded.contains(r#""xchg rdi, $2\n\tmov [rdi + 0], rax\n\t"#), "{}", expanded);
+        assert!(expanded.contains(r#"\n\t.ifc ${0:q},rbx\n\tmov qword ptr [rdi + 264], 1\n\t.endif\n\t"#),
+            "{}", expanded);
+        assert!(!expanded.contains("compile_error"), "{}", expanded);
+    }
+
+    #[test]
+    fn tracing() {
+        let expanded = expand(r#"
+            let mut a: u32: inout("r") = 1;
+            let b: u64: in("r", "r") = 2;
+            asm(trace) { "$a $b.lo $b.hi" }
+        "#);
+        let location = ":: core :: concat ! ( :: core :: file ! ( ) , \":\" , :: core :: line ! ( ) , \":\" , \
+            :: core :: column ! ( ) )";
+        assert!(expanded.contains(&format!(concat!(
+            r#"{{ # [ cfg ( debug_assertions ) ] {{ :: std :: eprintln ! ( "[{{}}] in a = {{:?}}" , {0} , a ) ; "#,
+            r#":: std :: eprintln ! ( "[{{}}] in b = {{:?}}" , {0} , b ) ; }} "#,
+            r#"asm ! ( "$0 $2 $3" : "=r" ( a ) : "0" ( a ) , "r" ( b as u32 ) , "r" ( ( ( b as u64 ) >> 32 ) as u32 ) : : ) ; "#,
+            r#"# [ cfg ( debug_assertions ) ] {{ :: std :: eprintln ! ( "[{{}}] out a = {{:?}}" , {0} , a ) ; }} }}"#
+        ), location)), "{}", expanded);
+
+        let expanded = expand(r#"
+            let x: in("r") = 1u8;
+            asm(trace = log_value) { "$x" }
+        "#);
+        assert!(expanded.contains(r#"{ log_value ( :: core :: concat ! ("#), "{}", expanded);
+        assert!(expanded.contains(r#"column ! ( ) ) , "in" , "x" , & x ) ; }"#), "{}", expanded);
+
+        let expanded = expand(r#"
+            let (lo, hi) = asm(trace) -> (out("{eax}"), out("{edx}")) { "rdtsc" };
+            let ticks = asm(trace) -> out("{eax}") { "rdtsc" };
+            let named = asm(trace) -> out("r") one { "mov $$1, $one" };
+        "#);
+        assert!(expanded.contains(r#"eprintln ! ( "[{}] out yield.0 = {:?}""#), "{}", expanded);
+        assert!(expanded.contains(r#"eprintln ! ( "[{}] out yield.1 = {:?}""#), "{}", expanded);
+        assert!(expanded.contains(r#"eprintln ! ( "[{}] out yield = {:?}""#), "{}", expanded);
+        assert!(expanded.contains(r#"eprintln ! ( "[{}] out one = {:?}""#), "{}", expanded);
+        assert!(!expanded.contains(r#"out __rusty_asm_yield"#), "{}", expanded);
+    }
+
+    #[test]
+    fn inline_operands() {
+        let expanded = expand(r#"
+            let mut a: inout("r") = a;
+            asm { "add ${b + 1}, $a\n\tmovdqa ${v:xmm_reg}, ${self.base as u64}\n\tmov ${0:q}, ${core::u8::MAX}" }
+            a
+        "#);
+        assert!(expanded.contains(concat!(
+            "{ let __rusty_asm_expr_0 = b + 1 ; ",
+            r#"# [ cfg ( all ( any ( target_arch = "x86" , "#
+        )), "{}", expanded);
+        assert!(expanded.contains(concat!(
+            r#"compile_error ! ( "register class `xmm_reg` only exists on x86 or x86_64" ) ; "#,
+            "let __rusty_asm_expr_1 = v ; let __rusty_asm_expr_2 = self . base as u64 ; ",
+            "let __rusty_asm_expr_3 = core :: u8 :: MAX ; ",
+            r#"asm ! ( "add $2, $0\n\tmovdqa $3, $4\n\tmov ${0:q}, $5" : "=r" ( a ) : "0" ( a ) , "#,
+            r#""r" ( __rusty_asm_expr_0 ) , "x" ( __rusty_asm_expr_1 ) , "r" ( __rusty_asm_expr_2 ) , "#,
+            r#""r" ( __rusty_asm_expr_3 ) : : ) ; }"#
+        )), "{}", expanded);
+
+        // LLVM's own escapes are left alone.
+        let (expanded, messages) = expand_with_errors(r#"
+            asm { "${:private}loop_${:uid}: ${:comment} ${0:q}" }
+        "#);
+        assert!(expanded.contains(r#"asm ! ( "${:private}loop_${:uid}: ${:comment} ${0:q}" : : : : )"#), "{}", expanded);
+        assert!(messages.is_empty(), "{:?}", messages);
+
+        let messages = errors(r#"
+            asm { "${a +}" }
+            asm { "${a:foo}" }
+            asm { "${a" }
+        "#);
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages[0].starts_with("expected a Rust expression in `${...}`"), "{:?}", messages);
+        assert!(messages[1].starts_with("unknown register class `foo`"), "{:?}", messages);
+        assert!(messages[2].starts_with("unclosed `${`"), "{:?}", messages);
+    }
+
+    #[test]
+    fn yielded_outputs() {
+        let expanded = expand(r#"
+            let (lo, hi) = asm -> (out("{eax}"), out("{edx}")) { "rdtsc" };
+            let sum = asm("intel") -> out("r") sum: u32 { "lea $sum, [${lo} + ${hi}]" };
+        "#);
+        assert!(expanded.contains(concat!(
+            "let ( lo , hi ) = { let __rusty_asm_result ; { let __rusty_asm_yield_0 ; let __rusty_asm_yield_1 ; ",
+            r#"asm ! ( "rdtsc" : "={eax}" ( __rusty_asm_yield_0 ) , "={edx}" ( __rusty_asm_yield_1 ) : : : ) ; "#,
+            "__rusty_asm_result = ( __rusty_asm_yield_0 , __rusty_asm_yield_1 , ) ; } __rusty_asm_result } ;"
+        )), "{}", expanded);
+        assert!(expanded.contains(concat!(
+            "let sum = { let __rusty_asm_result ; { let sum : u32 ; ",
+            "{ let __rusty_asm_expr_0 = lo ; let __rusty_asm_expr_1 = hi ; ",
+            r#"asm ! ( "lea $0, [$1 + $2]" : "=r" ( sum ) : "r" ( __rusty_asm_expr_0 ) , "r" ( __rusty_asm_expr_1 ) "#,
+            r#": : "intel" ) ; } __rusty_asm_result = sum ; } __rusty_asm_result } ;"#
+        )), "{}", expanded);
+
+        let messages = errors(r#"
+            let x = asm -> in("r") y { "" };
+            let x = asm(target_arch = "x86") -> out("{eax}") { "" } else asm(target_arch = "arm") { "" } else { 0 };
+            let (a, b) = asm(target_arch = "x86_64") -> (out("{eax}"), out("{edx}")) { "" }
+                else asm(target_arch = "aarch64") -> out("{x0}") { "" }
+                else asm(target_arch = "arm") -> (out("{r0}"),) { "" }
+                else { (0, 0) };
+            let y = 1 + (asm -> out("r") z: u32 { "" });
+            if [asm("volatile") -> out("r") { "" }][0] == 0 {}
+        "#);
+        let nested = format!("an `asm` expression has to be at the statement level{}",
+            help_suffix("assign its value to a variable in a `let` statement first, and use that instead"));
+        assert_eq!(messages, [
+            "an `asm` block can only yield `out` operands",
+            "either every alternative in a chain yields outputs or none of them do",
+            "this alternative yields a single output, but the first one yields a tuple of 2 outputs",
+            "this alternative yields a tuple of 1 output, but the first one yields a tuple of 2 outputs",
+            nested.as_str(),
+            nested.as_str()
+        ]);
+
+        // An invalid block still leaves an expression behind.
+        let (expanded, _) = expand_with_errors(r#"let x = asm -> in("r") y { "" };"#);
+        assert!(expanded.contains("let x = :: core :: unreachable ! ( ) ;"), "{}", expanded);
+    }
+
+    #[test]
+    fn header_operands() {
+        let expanded = expand(r#"
+            let a: in("r") = a;
+            asm(in("r") x = a + b, out("r") y, inout("r") n = 3, "volatile") { "lea ($a, $x), $y\n\tshl $y, $n" }
+            asm { "" }
+        "#);
+        assert!(expanded.contains(concat!(
+            "{ let x = a + b ; let y ; let mut n = 3 ; ",
+            r#"asm ! ( "lea ($2, $3), $0\n\tshl $0, $1" : "=r" ( y ) , "=r" ( n ) : "r" ( a ) , "r" ( x ) , "1" ( n ) "#,
+            r#": : "volatile" ) ; }"#
+        )), "{}", expanded);
+
+        let messages = errors(r#"
+            #![deny(unused_bridge_var)]
+            asm(in("r") x) { "$x" }
+            asm(in("r") x = 1, in("r") _y = 2) { "nop" }
+            let z: in("r") = 3;
+            asm(in("r") z = 4) { "$z" }
+        "#);
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert_eq!(messages[0], format!("an input that's declared in an `asm` block's header needs a value{}",
+            help_suffix("add `= <expr>` after `x`")));
+        assert!(messages[1].starts_with("bridge variable not used"), "{:?}", messages); // `x` in the second block
+        assert!(messages[2].starts_with("bridge variable not used"), "{:?}", messages); // The outer `z`
+    }
+
+    #[test]
+    fn cfg_attributes() {
+        let expanded = expand(r#"
+            let a: in("r") = 1;
+            #[cfg(unix)]
+            let b: in("r") = 2;
+            #[cfg_attr(debug_assertions, cfg(target_arch = "x86_64"))]
+            clobber("rcx");
+            asm { "add $a, $b" }
+        "#);
+        assert!(expanded.starts_with("{ let a = 1 ; # [ cfg ( unix ) ] let b = 2 ; "), "{}", expanded);
+        assert_eq!(expanded.matches("asm !").count(), 4, "{}", expanded);
+        assert!(expanded.contains(concat!(
+            r#"# [ cfg ( any ( all ( unix , debug_assertions , not ( target_arch = "x86_64" ) ) ) ) ] "#,
+            r#"{ asm ! ( "add $0, $1" : : "r" ( a ) , "r" ( b ) : : ) ; }"#
+        )), "{}", expanded);
+
+        let expanded = expand(r#"
+            let a: in("r") = 1;
+            #[cfg(unix)]
+            #[allow(unused)]
+            asm { "inc $a" }
+        "#);
+        assert!(expanded.contains(r#"# [ cfg ( unix ) ] # [ allow ( unused ) ] { asm ! ( "inc $0" "#), "{}", expanded);
+
+        let messages = errors(r#"
+            #[cfg(unix)]
+            #[allow(unused)]
+            clobber("rax");
+            #[cfg(unix)]
+            asm -> out("r") x { "" }
+        "#);
+        assert_eq!(messages, [
+            "only `#[cfg]` and `#[cfg_attr]` can be used on a clobber",
+            "an `asm` block that yields outputs can't have attributes"
+        ]);
+    }
+
+    #[test]
+    fn noreturn() {
+        let expanded = expand(r#"
+            let code: in("{eax}") = 1;
+            asm(noreturn) { "ud2" }
+        "#);
+        assert!(expanded.ends_with(
+            r#"{ { asm ! ( "ud2" : : "{eax}" ( code ) : : ) ; :: core :: hint :: unreachable_unchecked ( ) } } }"#
+        ), "{}", expanded);
+
+        let messages = errors(r#"
+            let mut x: inout("r") = 1;
+            asm(noreturn) { "jmp *$x" }
+            asm(noreturn, out("r") y) -> out("r") z { "" }
+            {
+                let w: in("{ecx}") = 2;
+                clobber("ecx");
+                asm("volatile") { "mov $x, $w" }
+                asm(noreturn) { "ud2" }
+            }
+        "#);
+        let help = help_suffix("every output bridge variable in scope is an output of the block, even if it isn't used");
+        assert_eq!(messages, [
+            format!("a `noreturn` block can't have outputs, but this one has `x`{}", help),
+            String::from("a `noreturn` block can't yield outputs"),
+            String::from("a `noreturn` block can't have outputs"),
+            format!("a `noreturn` block can't have outputs, but this one has `x`{}", help),
+            format!("a `noreturn` block can't have outputs, but this one has `x`, `w`{}", help)
+        ]);
+    }
+
+    #[test]
+    fn label_arms() {
+        let expanded = expand(r#"
+            let x: in("r") = 0;
+            asm { "test $x, $x\n\tjz $on_zero\n\tjs $negative" }
+            on_zero => { zero() }
+            negative => { negative() }
+        "#);
+        assert!(expanded.contains("{ let __rusty_asm_label : usize ; "), "{}", expanded);
+        assert!(expanded.contains(concat!(
+            r#"# [ cfg ( any ( target_arch = "x86" , target_arch = "x86_64" ) ) ] { asm ! ( "#,
+            r#""test $1, $1\n\tjz ${:private}__rusty_asm_label_0_${:uid}\n\tjs ${:private}__rusty_asm_label_1_${:uid}"#,
+            r#"\n\tmov $$0, $0\n\tjmp ${:private}__rusty_asm_end_${:uid}"#,
+            r#"\n${:private}__rusty_asm_label_0_${:uid}:\n\tmov $$1, $0\n\tjmp ${:private}__rusty_asm_end_${:uid}"#,
+            r#"\n${:private}__rusty_asm_label_1_${:uid}:\n\tmov $$2, $0\n\tjmp ${:private}__rusty_asm_end_${:uid}"#,
+            r#"\n${:private}__rusty_asm_end_${:uid}:" : "=r" ( __rusty_asm_label ) : "r" ( x ) : : ) ; }"#
+        )), "{}", expanded);
+        assert!(expanded.contains(r#"] { asm ! ( "test $1, $1\n\tjz ${:private}__rusty_asm_label_0_${:uid}"#));
+        assert!(expanded.contains("compile_error ! ( \"an `asm` block can't jump to Rust code on this target\" )"));
+        assert!(expanded.ends_with(
+            "match __rusty_asm_label { 1 => { zero ( ) } 2 => { negative ( ) } _ => { } } } }"
+        ), "{}", expanded);
+
+        let messages = errors(r#"
+            let x: in("r") = 0;
+            asm(target_arch = "x86_64") { "jz $x" } x => {} y => {} y => {}
+            else asm(target_arch = "aarch64", noreturn) { "b $z" } z => {}
+            else {}
+        "#);
+        assert_eq!(messages, [
+            "label `x` has the same name as a bridge variable",
+            "label `y` is defined more than once",
+            "a `noreturn` block can't jump to Rust code"
+        ]);
+    }
+
+    #[test]
+    fn code_locations() {
+        assert_eq!(source_range(r#""mov $b, $a""#, 4 .. 6), Some(5 .. 7));
+        assert_eq!(source_range(r###"r#"mov $b, $a"#"###, 4 .. 6), Some(7 .. 9));
+        assert_eq!(source_range(r#""\x41\u{e9} $b""#, 4 .. 6), Some(12 .. 14));
+        assert_eq!(source_range("\"\\\n    ab $b\"", 3 .. 5), Some(10 .. 12));
+
+        assert_eq!(
+            excerpt("nop\n\tadd $sum, $i\n", 9 .. 13),
+            "\n  --> line 2 of the `asm` code\n   |\n   | \tadd $sum, $i\n   | \t    ^^^^"
+        );
+    }
+
     #[test]
     fn code_coverage() {
         // Loop through all the files in `tests/`.
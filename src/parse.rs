@@ -11,29 +11,48 @@
 // ANY KIND, either express or implied. See the applicable license for the
 // specific language governing permissions and limitations under that license.
 
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Range;
 use std::str::Chars;
 
-use proc_macro2::{Span, TokenStream, TokenTree, Delimiter};
+use proc_macro2::{Span, Spacing, TokenStream, TokenTree, Delimiter};
 use quote::{ToTokens, TokenStreamExt};
-use syn::{Expr, Ident, LitStr, Type};
+use syn::{Attribute, Expr, Ident, LitStr, Meta, NestedMeta, Path, Type};
 use syn::parse::{self, Parse, ParseBuffer, ParseStream};
 use syn::punctuated::Punctuated;
-use syn::token::Brace;
+use syn::spanned::Spanned;
+use syn::token::{Brace, Paren};
 use unicode_xid::UnicodeXID;
 
+use crate::cfg::{self, Condition, OuterAttributes};
+use crate::diagnostics::{self, error_with_help, Level, Lint, LintLevels};
+use crate::registers::{self, RegisterClass};
+
 #[derive(Debug)]
 pub struct RustyAsmBlock {
-    contents: Vec<RustyAsmPiece>
+    // The block's inner attributes, except for the ones that only set the levels of our lints
+    inner_attrs: Vec<Attribute>,
+
+    contents: Vec<RustyAsmPiece>,
+
+    // The lint levels in effect in this block, after its inner attributes
+    lints: LintLevels,
+
+    // Every error found while parsing the invocation. Inner blocks don't keep their own errors; they're all collected
+    // here in the top-level block so they can be reported together. (`syn::Error` can only hold one message.)
+    errors: Vec<parse::Error>
 }
 
 mod keyword {
     custom_keyword!(out);
     custom_keyword!(inout);
+    custom_keyword!(slice_in);
+    custom_keyword!(flag);
     custom_keyword!(clobber);
     custom_keyword!(asm);
+    custom_keyword!(fallback);
 }
 
 impl Parse for RustyAsmBlock {
@@ -41,15 +60,32 @@ impl Parse for RustyAsmBlock {
     fn parse(input: ParseStream) -> parse::Result<Self> {
         let bridge_vars_out = Vec::<BridgeVar>::new();
         let bridge_vars_in = Vec::<BridgeVar>::new();
-        let clobbers = HashSet::<Clobber>::new();
-        Self::parse_subblock(input, bridge_vars_out, bridge_vars_in, clobbers)
+        let clobbers = Vec::<Clobber>::new();
+        let mut errors = Vec::new();
+        let mut block = Self::parse_subblock(
+            input,
+            bridge_vars_out,
+            bridge_vars_in,
+            clobbers,
+            LintLevels::default(),
+            &mut errors
+        )?;
+        block.check_bridge_var_usage(&mut errors);
+        block.errors = errors;
+        Ok(block)
     }
 }
 
 impl ToTokens for RustyAsmBlock {
     fn to_tokens(&self, tokens: &mut TokenStream) {
+        // Any errors are emitted alongside the rest of the block, so that the compiler can still check everything we
+        // managed to parse. Inner attributes have to come first, though.
+        let inner_attrs = &self.inner_attrs;
+        let errors = self.errors.iter().map(|e| e.to_compile_error());
         let contents = &self.contents;
         let temp_tokens = quote!({
+            #(#inner_attrs)*
+            #(#errors)*
             #(#contents)*
         });
         tokens.append_all(temp_tokens);
@@ -57,32 +93,80 @@ impl ToTokens for RustyAsmBlock {
 }
 
 impl RustyAsmBlock {
+    /// Returns every error that was found in the invocation. The block can still be expanded if there are any, but the
+    /// expansion will fail to compile.
+    pub fn errors(&self) -> &[parse::Error] {
+        &self.errors
+    }
+
     // Parses the inside of a block that is contained within another rusty_asm block. The
-    // parameters allow bridge variables and clobbers from outer scopes to be used in inner scopes.
+    // parameters allow bridge variables, clobbers, and lint levels from outer scopes to be used in inner scopes.
     fn parse_subblock(input: ParseStream, mut bridge_vars_out: Vec<BridgeVar>, mut bridge_vars_in: Vec<BridgeVar>,
-            mut clobbers: HashSet<Clobber>) -> parse::Result<Self> {
+            mut clobbers: Vec<Clobber>, mut lints: LintLevels, errors: &mut Vec<parse::Error>) -> parse::Result<Self> {
+        let mut inner_attrs = Vec::new();
         let mut contents = Vec::new();
+
+        // Inner attributes can set the levels of our lints for the rest of the block.
+        if input.peek(Token![#]) && input.peek2(Token![!]) {
+            match input.call(Attribute::parse_inner) {
+                Ok(attrs) => {
+                    inner_attrs = attrs.into_iter().filter_map(|attr| apply_lint_attribute(&mut lints, attr)).collect();
+                },
+                Err(e) => errors.push(e)
+            }
+        }
+
         while !input.is_empty() {
-            let piece = RustyAsmPiece::parse(input, &mut bridge_vars_out, &mut bridge_vars_in, &mut clobbers)?;
-            contents.push(piece);
+            match RustyAsmPiece::parse(input, &mut bridge_vars_out, &mut bridge_vars_in, &mut clobbers, &lints, errors) {
+                Ok(piece) => contents.push(piece),
+                Err(e) => {
+                    // Skip the rest of the statement and keep going, so we can find any other errors, too.
+                    errors.push(e);
+                    contents.push(RustyAsmPiece::parse_fallback(input));
+                }
+            }
         }
 
-        Ok(RustyAsmBlock { contents })
+        Ok(RustyAsmBlock { inner_attrs, contents, lints, errors: Vec::new() })
+    }
+
+    // Reports bridge variables that no `asm` block uses and outputs whose values are never read. This has to wait
+    // until the whole invocation has been parsed, since any `asm` block in a variable's scope can use it.
+    fn check_bridge_var_usage(&self, errors: &mut Vec<parse::Error>) {
+        for (i, piece) in self.contents.iter().enumerate() {
+            match piece {
+                RustyAsmPiece::RustyAsmBlock(_, block) => block.check_bridge_var_usage(errors),
+                RustyAsmPiece::BridgeVarDecl(decl) => decl.check_usage(&self.contents[i + 1 ..], &self.lints, errors),
+                RustyAsmPiece::AsmBlock(block) => block.check_operand_usage(errors),
+                _ => {}
+            }
+        }
     }
 }
 
 #[derive(Debug)]
 enum RustyAsmPiece {
     RustyAsmBlock(Brace, RustyAsmBlock),
-    BridgeVarDecl(BridgeVarDecl),
+    BridgeVarDecl(Box<BridgeVarDecl>),
     ClobberDecl(ClobberDecl),
-    AsmBlock(AsmBlock),
+    AsmBlock(Box<AsmBlock>),
     TokenTrees(Vec<TokenTree>)
 }
 
 impl RustyAsmPiece {
     fn parse(input: ParseStream, bridge_vars_out: &mut Vec<BridgeVar>, bridge_vars_in: &mut Vec<BridgeVar>,
-            clobbers: &mut HashSet<Clobber>) -> parse::Result<Self> {
+            clobbers: &mut Vec<Clobber>, lints: &LintLevels, errors: &mut Vec<parse::Error>) -> parse::Result<Self> {
+        // Outer attributes are ours to handle if they're on a bridge variable, a clobber, or an `asm` block. Otherwise,
+        // they're passed on with the rest of the Rust code.
+        let mut attrs = OuterAttributes::default();
+        if input.peek(Token![#]) && !input.peek2(Token![!]) {
+            let fork = input.fork();
+            if fork.call(Attribute::parse_outer).is_ok()
+                    && (BridgeVarDecl::peek(&fork) || ClobberDecl::peek(&fork) || AsmBlock::peek(&fork)) {
+                attrs = input.call(OuterAttributes::parse)?;
+            }
+        }
+
         if input.peek(Brace) {
             // A block
             let contents;
@@ -91,52 +175,88 @@ impl RustyAsmPiece {
                 &contents,
                 bridge_vars_out.clone(),
                 bridge_vars_in.clone(),
-                clobbers.clone()
+                clobbers.clone(),
+                lints.clone(),
+                errors
             )?;
             Ok(RustyAsmPiece::RustyAsmBlock(brace, block))
         } else if input.peek(Token![let]) {
             // Possibly a bridge variable declaration
-            if let Ok(decl) = input.fork().parse::<BridgeVarDecl>() {
-                // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
-                let _ = input.parse::<BridgeVarDecl>();
-                decl.push_bridge_var(bridge_vars_out, bridge_vars_in);
-                Ok(RustyAsmPiece::BridgeVarDecl(decl))
-            } else {
-                // Not a bridge variable
-                let (tt, _) = input.cursor().token_tree().unwrap();
-                let _ = input.parse::<Token![let]>();
-                Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+            match input.fork().parse::<BridgeVarDecl>() {
+                Ok(mut decl) => {
+                    // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
+                    let _ = input.parse::<BridgeVarDecl>();
+                    decl.attrs = attrs;
+                    decl.push_bridge_var(bridge_vars_out, bridge_vars_in, errors);
+                    Ok(RustyAsmPiece::BridgeVarDecl(Box::new(decl)))
+                },
+                Err(e) => if BridgeVarDecl::peek(input) {
+                    // It's definitely meant to be a bridge variable, so it can't just be passed on to Rust.
+                    Err(e)
+                } else {
+                    // Not a bridge variable
+                    let (tt, _) = input.cursor().token_tree().unwrap();
+                    let _ = input.parse::<Token![let]>();
+                    Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+                }
             }
         } else if input.peek(keyword::clobber) {
             // Possibly a clobber declaration
-            if let Ok(decl) = input.fork().parse::<ClobberDecl>() {
-                // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
-                let _ = input.parse::<ClobberDecl>();
-                decl.push_clobber(clobbers);
-                Ok(RustyAsmPiece::ClobberDecl(decl))
-            } else {
-                // Not a clobber
-                let (tt, _) = input.cursor().token_tree().unwrap();
-                let _ = input.parse::<keyword::clobber>();
-                Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+            match input.fork().parse::<ClobberDecl>() {
+                Ok(mut decl) => {
+                    // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
+                    let _ = input.parse::<ClobberDecl>();
+                    if let Some(attr) = attrs.others.first() {
+                        errors.push(parse::Error::new_spanned(
+                            attr,
+                            "only `#[cfg]` and `#[cfg_attr]` can be used on a clobber"
+                        ));
+                    }
+                    decl.cfg = attrs.cfg;
+                    decl.push_clobber(clobbers);
+                    Ok(RustyAsmPiece::ClobberDecl(decl))
+                },
+                Err(e) => if ClobberDecl::peek(input) {
+                    Err(e)
+                } else {
+                    // Not a clobber
+                    let (tt, _) = input.cursor().token_tree().unwrap();
+                    let _ = input.parse::<keyword::clobber>();
+                    Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+                }
             }
         } else if input.peek(keyword::asm) {
             // Possibly an ASM block
-            if let Ok(mut block) = AsmBlock::parse(
-                        &input.fork(),
+            let fork = input.fork();
+            match AsmBlock::parse(&fork, bridge_vars_out.clone(), bridge_vars_in.clone(), clobbers.clone(), lints.clone()) {
+                Ok(mut block) => {
+                    // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
+                    let _ = AsmBlock::parse(
+                        input,
                         bridge_vars_out.clone(),
                         bridge_vars_in.clone(),
-                        clobbers.clone()
-                    ) {
-                // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
-                let _ = AsmBlock::parse(input, bridge_vars_out.clone(), bridge_vars_in.clone(), clobbers.clone());
-                block.fix_overlapping_clobbers();
-                Ok(RustyAsmPiece::AsmBlock(block))
-            } else {
-                // Not an ASM block
-                let (tt, _) = input.cursor().token_tree().unwrap();
-                let _ = input.parse::<keyword::asm>();
-                Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+                        clobbers.clone(),
+                        lints.clone()
+                    );
+                    if block.yields.is_some() && !attrs.is_empty() {
+                        errors.push(parse::Error::new(
+                            block.asm_keyword.span,
+                            "an `asm` block that yields outputs can't have attributes"
+                        ));
+                    }
+                    block.attrs = attrs;
+                    block.finish(errors);
+                    block.check_alternatives(errors);
+                    Ok(RustyAsmPiece::AsmBlock(Box::new(block)))
+                },
+                Err(e) => if AsmBlock::peek(input) {
+                    Err(e)
+                } else {
+                    // Not an ASM block
+                    let (tt, _) = input.cursor().token_tree().unwrap();
+                    let _ = input.parse::<keyword::asm>();
+                    Ok(RustyAsmPiece::TokenTrees(vec![tt]))
+                }
             }
         } else if input.peek(Token![if]) || input.peek(Token![while]) {
             // We don't support `if let` or `while let`, so avoid parsing any tokens until the upcoming block.
@@ -155,15 +275,52 @@ impl RustyAsmPiece {
                 // The block never came.
                 Err(cursor.error("unexpected end of input"))
             })?;
+            check_nested_asm_expressions(tts.iter().cloned().collect(), errors);
             Ok(RustyAsmPiece::TokenTrees(tts))
         } else {
             // Any other token tree
             let tt = input.step(|cursor| cursor.token_tree().ok_or(cursor.error("unexpected end of input")))?;
+            if let TokenTree::Group(ref group) = tt {
+                check_nested_asm_expressions(group.stream(), errors);
+            }
             Ok(RustyAsmPiece::TokenTrees(vec![tt]))
         }
     }
 }
 
+impl RustyAsmPiece {
+    // Skips past the invalid statement at the start of `input` and returns whatever Rust code can be salvaged from it.
+    // That way, the rest of the block keeps its meaning (and IDEs can still analyze it) even though it won't compile.
+    fn parse_fallback(input: ParseStream) -> Self {
+        if BridgeVarDecl::peek(input) {
+            // A bridge variable becomes an ordinary variable.
+            let tokens = BridgeVarDecl::fallback_tokens(&input.fork());
+            skip_statement(input);
+            RustyAsmPiece::TokenTrees(tokens.into_iter().collect())
+        } else if AsmBlock::peek(input) {
+            // An `asm` block becomes a no-op. It doesn't have to end with a semicolon, so we skip exactly its tokens,
+            // along with any alternatives. A block that yields outputs is an expression, so it needs something in its
+            // place that can have any type.
+            let yields = input.peek2(Token![->]) || (input.peek2(Paren) && input.peek3(Token![->]));
+            skip_through_block(input);
+            while input.peek(Token![else]) || (input.peek(Ident) && input.peek2(Token![=>])) {
+                skip_through_block(input);
+            }
+            if input.peek(keyword::fallback) && input.peek2(Brace) {
+                skip_token_trees(input, 2);
+            }
+            if yields {
+                RustyAsmPiece::TokenTrees(quote!(::core::unreachable!()).into_iter().collect())
+            } else {
+                RustyAsmPiece::TokenTrees(Vec::new())
+            }
+        } else {
+            skip_statement(input);
+            RustyAsmPiece::TokenTrees(Vec::new())
+        }
+    }
+}
+
 impl ToTokens for RustyAsmPiece {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         match self {
@@ -186,44 +343,119 @@ struct BridgeVarDecl {
     mut_keyword: Option<Token![mut]>,
     ident: Ident,
     explicit_type: Option<(Token![:], Type)>,
+    // The type that the variable has while it's in a register, if it's different from the explicit type (as in
+    // `let x: bool as u8: in("r")`)
+    register_type: Option<(Token![as], Type)>,
     constraint_keyword: ConstraintKeyword,
+    // Whether the constraint names a condition code (as in `out(flag "z")`) instead of a register
+    is_flag: bool,
+    // The register class named by the constraint (as in `in(xmm_reg)`), if any. `constraint_string` holds the
+    // equivalent LLVM constraint.
+    register_class: Option<&'static RegisterClass>,
     constraint_string: LitStr,
+    // The constraint for a slice's length or the high half of a wide variable. (`constraint_string` is for the
+    // slice's pointer or the wide variable's low half.)
+    second_constraint_string: Option<LitStr>,
     assignment: Option<(Token![=], Expr)>,
-    semicolon: Token![;]
+    semicolon: Token![;],
+    attrs: OuterAttributes
 }
 
+// A parsed constraint: its keyword, whether it's for a flag, its register class, and its constraint strings
+type Constraint = (ConstraintKeyword, bool, Option<&'static RegisterClass>, LitStr, Option<LitStr>);
+
 #[derive(Debug, Clone)]
 enum ConstraintKeyword {
     In,
     Out,
-    InOut
+    InOut,
+    SliceIn
 }
 
 impl Parse for BridgeVarDecl {
     fn parse(input: ParseStream) -> parse::Result<Self> {
         // `let [mut] <identifier>:`
         let let_keyword = input.parse::<Token![let]>()?;
-        let mut_keyword;
-        if input.peek(Token![mut]) {
-            mut_keyword = input.parse::<Token![mut]>().ok();
-        } else {
-            mut_keyword = None;
-        }
+        let mut_keyword = input.parse::<Option<Token![mut]>>()?;
         let ident = input.parse::<Ident>()?;
         let colon = input.parse::<Token![:]>()?;
 
-        // `[<type>:]`
+        // `[<type> [as <register_type>]:]`
         let explicit_type;
+        let register_type;
         if let Ok(parsed_type) = input.fork().parse::<Type>() {
             // TODO: We're re-parsing an unbounded number of tokens here. Avoid this if possible.
             let _ = input.parse::<Type>();
             explicit_type = Some((colon, parsed_type));
+            if let Ok(as_keyword) = input.parse::<Token![as]>() {
+                register_type = Some((as_keyword, input.parse::<Type>()?));
+            } else {
+                register_type = None;
+            }
             input.parse::<Token![:]>()?;
         } else {
             explicit_type = None;
+            register_type = None;
+        }
+
+        let constraint = Self::parse_constraint(input)?;
+        let mut decl = Self::new(let_keyword, mut_keyword, ident, explicit_type, register_type, constraint);
+        decl.check_constraint()?;
+        decl.parse_assignment(input)?;
+        decl.semicolon = input.parse::<Token![;]>()?;
+        Ok(decl)
+    }
+}
+
+impl BridgeVarDecl {
+    // Parses an operand that's declared inline, as in `out("r") x: u32` in `asm -> (...)`:
+    // `<constraint> [[mut] <identifier>] [: <type> [as <register_type>]]`. An operand without a name is called `unnamed`.
+    fn parse_inline(input: ParseStream, unnamed: Option<Ident>) -> parse::Result<Self> {
+        let constraint = Self::parse_constraint(input)?;
+        let let_keyword = Token![let](constraint.3.span());
+        let mut_keyword = input.parse::<Option<Token![mut]>>()?;
+        let ident = match unnamed {
+            Some(unnamed) if mut_keyword.is_none() && !input.peek(Ident) => unnamed,
+            _ => input.parse::<Ident>()?
+        };
+        let explicit_type = if input.peek(Token![:]) {
+            Some((input.parse::<Token![:]>()?, input.parse::<Type>()?))
+        } else {
+            None
+        };
+        let register_type = if explicit_type.is_some() && input.peek(Token![as]) {
+            Some((input.parse::<Token![as]>()?, input.parse::<Type>()?))
+        } else {
+            None
+        };
+        let decl = Self::new(let_keyword, mut_keyword, ident, explicit_type, register_type, constraint);
+        decl.check_constraint()?;
+        Ok(decl)
+    }
+
+    fn new(let_keyword: Token![let], mut_keyword: Option<Token![mut]>, ident: Ident,
+            explicit_type: Option<(Token![:], Type)>, register_type: Option<(Token![as], Type)>,
+            constraint: Constraint) -> Self {
+        let (constraint_keyword, is_flag, register_class, constraint_string, second_constraint_string) = constraint;
+        BridgeVarDecl {
+            let_keyword,
+            mut_keyword,
+            ident,
+            explicit_type,
+            register_type,
+            constraint_keyword,
+            is_flag,
+            register_class,
+            constraint_string,
+            second_constraint_string,
+            assignment: None,
+            semicolon: Default::default(),
+            attrs: OuterAttributes::default()
         }
+    }
 
-        // `<constraint>`
+    // Parses `<constraint>`, like `in("r")` or `out(flag "z")`.
+    fn parse_constraint(input: ParseStream) -> parse::Result<Constraint> {
         let constraint_keyword;
         let lookahead = input.lookahead1();
         if lookahead.peek(Token![in]) {
@@ -235,40 +467,131 @@ impl Parse for BridgeVarDecl {
         } else if lookahead.peek(keyword::inout) {
             let _ = input.parse::<keyword::inout>();
             constraint_keyword = ConstraintKeyword::InOut;
+        } else if lookahead.peek(keyword::slice_in) {
+            let _ = input.parse::<keyword::slice_in>();
+            constraint_keyword = ConstraintKeyword::SliceIn;
         } else {
             return Err(lookahead.error());
         }
 
-        // `(<constraint_string>)` - e.g. `("r")`
+        // `(<constraint_string>)` - e.g. `("r")`, or `(<ptr_constraint>, <len_constraint>)` for a slice, or
+        // `(<lo_constraint>, <hi_constraint>)` for a wide variable, or `(flag <condition>)` for a flag output, or
+        // `(<register_class>)` - e.g. `(xmm_reg)`
         let content;
         parenthesized!(content in input);
-        let constraint_string = content.parse::<LitStr>()?;
-
-        let assignment;
-        if let Ok(assign_op) = input.parse::<Token![=]>() {
-            let init_expr = input.parse::<Expr>()?;
-            assignment = Some((assign_op, init_expr));
+        let is_flag = content.peek(keyword::flag);
+        if is_flag {
+            content.parse::<keyword::flag>()?;
+        }
+        let register_class;
+        let constraint_string;
+        if !is_flag && content.peek(Ident) {
+            let class_name = content.parse::<Ident>()?;
+            let class = RegisterClass::lookup(&class_name.to_string()).ok_or_else(|| {
+                parse::Error::new(class_name.span(), format!("unknown register class `{}`", class_name))
+            })?;
+            register_class = Some(class);
+            constraint_string = LitStr::new(class.llvm_constraint, class_name.span());
         } else {
-            assignment = None;
+            register_class = None;
+            constraint_string = content.parse::<LitStr>()?;
+        }
+        let is_slice = matches!(constraint_keyword, ConstraintKeyword::SliceIn);
+        if register_class.is_some() && (is_slice || content.peek(Token![,])) {
+            return Err(parse::Error::new(constraint_string.span(),
+                "a register class can only be used for a bridge variable that fits in one register"));
         }
+        let second_constraint_string = if is_slice || content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
+            Some(content.parse::<LitStr>()?)
+        } else {
+            None
+        };
+        expect_end(&content)?;
 
-        let semicolon = input.parse::<Token![;]>()?;
+        Ok((constraint_keyword, is_flag, register_class, constraint_string, second_constraint_string))
+    }
 
-        Ok(BridgeVarDecl {
-            let_keyword,
-            mut_keyword,
-            ident,
-            explicit_type,
-            constraint_keyword,
-            constraint_string,
-            assignment,
-            semicolon
-        })
+    // Checks that the constraint makes sense for the variable's type.
+    fn check_constraint(&self) -> parse::Result<()> {
+        if let Some((as_keyword, _)) = self.register_type {
+            if self.is_flag || self.second_constraint_string.is_some() {
+                return Err(parse::Error::new(as_keyword.span,
+                    "a bridge variable can only be converted if it fits in one register"));
+            }
+        }
+        if self.is_flag {
+            Self::check_flag(&self.constraint_keyword, &self.explicit_type, &self.constraint_string,
+                &self.second_constraint_string)?;
+        }
+        if self.second_constraint_string.is_none() {
+            // The register has to fit the type that's actually in it. (If that's `_`, the type's `AsmOperand`
+            // implementation is checked at compile time instead.)
+            match self.register_type {
+                Some((_, Type::Infer(_))) => {},
+                Some((_, ref ty)) => {
+                    Self::check_register_class_type(self.register_class, Some(ty), &self.constraint_string)?
+                },
+                None => Self::check_register_class_type(self.register_class,
+                    self.explicit_type.as_ref().map(|(_, ty)| ty), &self.constraint_string)?
+            }
+        }
+
+        // A wide variable is split in half, so we need to know exactly how wide it is.
+        if let Some(ref hi_constraint_string) = self.second_constraint_string {
+            let is_slice = matches!(self.constraint_keyword, ConstraintKeyword::SliceIn);
+            if !is_slice && self.explicit_type.as_ref().and_then(|(_, ty)| WideType::of(ty)).is_none() {
+                return Err(parse::Error::new(hi_constraint_string.span(),
+                    "a bridge variable that's split across two registers needs an explicit type of `u64`, `i64`, \
+                    `u128`, or `i128`"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // `[= <init_expr>]`
+    fn parse_assignment(&mut self, input: ParseStream) -> parse::Result<()> {
+        if let Ok(assign_op) = input.parse::<Token![=]>() {
+            let init_expr = input.parse::<Expr>()?;
+            self.assignment = Some((assign_op, init_expr));
+        } else if let ConstraintKeyword::SliceIn = self.constraint_keyword {
+            // The pointer and length are taken from the slice as soon as an `asm` block uses them.
+            return Err(input.error("expected `=`; a slice bridge variable needs an initializer"));
+        }
+        Ok(())
     }
 }
 
 impl ToTokens for BridgeVarDecl {
     fn to_tokens(&self, tokens: &mut TokenStream) {
+        let mut checks = TokenStream::new();
+        for constraint_string in self.constraint_strings() {
+            let constraint = constraint_string.value();
+            if let Some(reg) = registers::explicit_register(&constraint) {
+                checks.append_all(reserved_register_errors(constraint_string, reg,
+                    "bind the variable to a different register, or use a register class like `\"r\"` to let the \
+                    compiler choose one"));
+            }
+        }
+        if self.is_flag {
+            checks.append_all(flag_condition_errors(&self.constraint_string));
+        }
+        if let Some(class) = self.register_class {
+            checks.append_all(register_class_errors(class, self.constraint_string.span()));
+        }
+
+        if let Some((rust_type, Type::Infer(_))) = self.conversion() {
+            checks.append_all(self.operand_class_assertion(&rust_type));
+        }
+
+        // The checks only apply in the declaration's configurations.
+        match self.attrs.cfg {
+            Some(ref cfg) if !checks.is_empty() => tokens.append_all(quote!(#[cfg(#cfg)] { #checks })),
+            _ => tokens.append_all(checks)
+        }
+        self.attrs.to_tokens(tokens);
+
         // Emit the equivalent Rust `let` statement, keeping the original span for each token.
         self.let_keyword.to_tokens(tokens);
         if let Some(mut_keyword) = self.mut_keyword {
@@ -278,6 +601,9 @@ impl ToTokens for BridgeVarDecl {
         if let Some((colon, ref explicit_type)) = self.explicit_type {
             colon.to_tokens(tokens);
             explicit_type.to_tokens(tokens);
+        } else if self.is_flag {
+            // `asm!` needs to know the type of every output.
+            tokens.append_all(quote_spanned!(self.constraint_string.span()=> : bool));
         }
         if let Some((assign_op, ref init_expr)) = self.assignment {
             assign_op.to_tokens(tokens);
@@ -288,49 +614,340 @@ impl ToTokens for BridgeVarDecl {
 }
 
 impl BridgeVarDecl {
-    fn push_bridge_var(&self, bridge_vars_out: &mut Vec<BridgeVar>, bridge_vars_in: &mut Vec<BridgeVar>) {
-        match self.constraint_keyword {
-            ConstraintKeyword::In => {
-                Self::push_var(bridge_vars_in, BridgeVar {
-                    ident: self.ident.clone(),
-                    llvm_constraint: (self.constraint_string.value(), self.constraint_string.span())
-                });
+    // Determines whether the upcoming tokens are meant to be a bridge variable declaration, even if they aren't a valid
+    // one. That's the case if one of the constraint keywords appears where Rust would expect a type.
+    fn peek(input: ParseStream) -> bool {
+        fn parse_prefix(input: ParseStream) -> parse::Result<()> {
+            input.parse::<Token![let]>()?;
+            input.parse::<Option<Token![mut]>>()?;
+            input.parse::<Ident>()?;
+            input.parse::<Token![:]>()?;
+            if input.fork().parse::<Type>().is_ok() {
+                let _ = input.parse::<Type>();
+                if input.parse::<Token![as]>().is_ok() {
+                    input.parse::<Type>()?;
+                }
+                input.parse::<Token![:]>()?;
+            }
+            Ok(())
+        }
+
+        let fork = input.fork();
+        parse_prefix(&fork).is_ok() &&
+            (fork.peek(Token![in]) || fork.peek(keyword::out) || fork.peek(keyword::inout) || fork.peek(keyword::slice_in))
+    }
+
+    // Produces an ordinary `let` statement from as much of an invalid bridge variable declaration as can be parsed,
+    // keeping the original spans. The constraint is dropped, along with the initializer if it's invalid.
+    fn fallback_tokens(input: ParseStream) -> TokenStream {
+        let mut tokens = TokenStream::new();
+        let _ = (|| -> parse::Result<()> {
+            input.parse::<Token![let]>()?.to_tokens(&mut tokens);
+            input.parse::<Option<Token![mut]>>()?.to_tokens(&mut tokens);
+            input.parse::<Ident>()?.to_tokens(&mut tokens);
+            let colon = input.parse::<Token![:]>()?;
+            if let Ok(explicit_type) = input.fork().parse::<Type>() {
+                let _ = input.parse::<Type>();
+                colon.to_tokens(&mut tokens);
+                explicit_type.to_tokens(&mut tokens);
+            }
+            // Any register type is skipped along with the constraint.
+
+            // Skip the constraint, whatever it looks like.
+            input.step(|cursor| {
+                let mut rest = *cursor;
+                while let Some((tt, next)) = rest.token_tree() {
+                    match tt {
+                        TokenTree::Punct(ref punct) if punct.as_char() == '=' || punct.as_char() == ';' => break,
+                        _ => rest = next
+                    };
+                }
+                Ok(((), rest))
+            })?;
+
+            if let Ok(assign_op) = input.parse::<Token![=]>() {
+                let init_expr = input.parse::<Expr>()?;
+                assign_op.to_tokens(&mut tokens);
+                init_expr.to_tokens(&mut tokens);
+            }
+            Ok(())
+        })();
+        <Token![;]>::default().to_tokens(&mut tokens);
+        tokens
+    }
+
+    fn push_bridge_var(&self, bridge_vars_out: &mut Vec<BridgeVar>, bridge_vars_in: &mut Vec<BridgeVar>,
+            errors: &mut Vec<parse::Error>) {
+        self.check_register_conflicts(bridge_vars_out, bridge_vars_in, errors);
+        let (outputs, inputs) = self.operands();
+
+        // The outputs go first, since an input can be tied to one of them.
+        let indices = match self.attrs.cfg {
+            None => {
+                // Any other operands bound to the same variable are shadowed by this declaration. Operands that are
+                // replaced by new ones with the same name keep their positions, unless they only exist in some
+                // configurations.
+                while let Some(index) = bridge_vars_out.iter().position(|var| var.ident == self.ident
+                        && (var.cfg.is_some() || !outputs.iter().any(|new| new.name() == var.name()))) {
+                    Self::remove_output(bridge_vars_out, bridge_vars_in, index);
+                }
+                bridge_vars_in.retain(|var| var.ident != self.ident
+                    || (var.cfg.is_none() && inputs.iter().any(|(new, _)| new.name() == var.name())));
+                outputs.into_iter()
+                    .map(|var| Self::push_var(bridge_vars_out, var).unwrap_or(bridge_vars_out.len() - 1))
+                    .collect::<Vec<_>>()
             },
+            Some(ref cfg) => {
+                // The other operands are only shadowed in this declaration's configurations, so they stay where they
+                // are, and the new ones go after all of them.
+                for var in bridge_vars_out.iter_mut().chain(bridge_vars_in.iter_mut()) {
+                    if var.ident == self.ident {
+                        var.cfg = Some(Condition::and(var.cfg.take(), Condition::Not(Box::new(cfg.clone()))));
+                    }
+                }
+                outputs.into_iter()
+                    .map(|var| {
+                        bridge_vars_out.push(var);
+                        bridge_vars_out.len() - 1
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        for (mut var, tied_output) in inputs {
+            if let Some(i) = tied_output {
+                var.llvm_constraint.0 = format!("{}", indices[i]);
+            }
+            if self.attrs.cfg.is_none() {
+                Self::push_var(bridge_vars_in, var);
+            } else {
+                bridge_vars_in.push(var);
+            }
+        }
+    }
 
-            ConstraintKeyword::Out => {
-                let duplicate_index = Self::push_var(bridge_vars_out, BridgeVar {
-                    ident: self.ident.clone(),
-                    llvm_constraint: (String::from("=") + self.constraint_string.value().as_str(), self.constraint_string.span())
-                });
-
-                // If a duplicate was found, and it was an `inout` variable, remove the `in` constraint. It technically wouldn't
-                // be incorrect to keep it, but it would make it a little harder for LLVM to optimize the register usage.
-                if let Some(index) = duplicate_index {
-                    Self::swap_remove_var(bridge_vars_in, BridgeVar {
-                        ident: self.ident.clone(),
-                        llvm_constraint: (format!("{}", index), Span::call_site()) // The span doesn't matter here.
+    // Returns the outputs and inputs that this declaration binds. An input can be tied to one of the outputs (as with
+    // `inout`), in which case the index of that output in the returned list is given along with it.
+    fn operands(&self) -> (Vec<BridgeVar>, Vec<(BridgeVar, Option<usize>)>) {
+        let parts = match (&self.constraint_keyword, &self.second_constraint_string) {
+            (ConstraintKeyword::SliceIn, Some(len)) => {
+                vec![(Some(Part::Ptr), &self.constraint_string), (Some(Part::Len), len)]
+            },
+            (_, Some(hi)) => {
+                let wide_type = self.explicit_type.as_ref().and_then(|(_, ty)| WideType::of(ty)).unwrap();
+                vec![(Some(Part::Lo(wide_type)), &self.constraint_string), (Some(Part::Hi(wide_type)), hi)]
+            },
+            (_, None) => vec![(None, &self.constraint_string)]
+        };
+
+        let mut outputs = Vec::new();
+        let mut inputs = Vec::new();
+        for (part, constraint_string) in parts {
+            let operand = |constraint: String, points_to_mut: bool| BridgeVar {
+                ident: self.ident.clone(),
+                part,
+                llvm_constraint: (constraint, constraint_string.span()),
+                points_to_mut,
+                flag_condition: None,
+                modifier: self.class(constraint_string).and_then(|class| class.modifier),
+                conversion: self.conversion(),
+                cfg: self.attrs.cfg.clone()
+            };
+            // Only a slice's pointer (or a whole variable) can point to memory.
+            let points_to_mut = (part.is_none() || part == Some(Part::Ptr)) && self.points_to_mutable_memory();
+            match self.constraint_keyword {
+                ConstraintKeyword::In | ConstraintKeyword::SliceIn => {
+                    inputs.push((operand(constraint_string.value(), points_to_mut), None));
+                },
+                ConstraintKeyword::Out if self.is_flag => {
+                    // LLVM's own flag output constraint, like `=@ccz`. (See `AsmBlock::to_tokens` for targets where
+                    // it isn't used.)
+                    let condition = constraint_string.value();
+                    outputs.push(BridgeVar {
+                        flag_condition: Some(condition.clone()),
+                        ..operand(format!("=@cc{}", condition), false)
                     });
+                },
+                ConstraintKeyword::Out => {
+                    outputs.push(operand(format!("={}", constraint_string.value()), false));
+                },
+                ConstraintKeyword::InOut => {
+                    outputs.push(operand(format!("={}", constraint_string.value()), false));
+                    // The constraint is filled in once we know where the output ends up.
+                    inputs.push((operand(String::new(), points_to_mut), Some(outputs.len() - 1)));
                 }
-            },
+            }
+        }
+        (outputs, inputs)
+    }
+
+    // Determines whether the variable holds a pointer or reference to mutable memory, judging by its explicit type if it
+    // has one, or otherwise by its initializer.
+    fn points_to_mutable_memory(&self) -> bool {
+        match (&self.explicit_type, &self.assignment) {
+            (Some((_, explicit_type)), _) => is_mutable_pointer_type(explicit_type),
+            (None, Some((_, init_expr))) => is_mutable_pointer_expr(init_expr),
+            (None, None) => false
+        }
+    }
 
-            ConstraintKeyword::InOut => {
-                let mut index = bridge_vars_out.len();
-                let span = self.constraint_string.span();
-                if let Some(unexpected_index) = Self::push_var(bridge_vars_out, BridgeVar {
-                            ident: self.ident.clone(),
-                            llvm_constraint: (String::from("=") + self.constraint_string.value().as_str(), span)
-                        }) {
-                    // If a duplicate `out` variable was found, use that index instead of a new one.
-                    index = unexpected_index;
+    // Reports this variable if it isn't used by any `asm` block in its scope (the pieces after the declaration), or if
+    // it's an output that nothing reads afterward. Like Rust, we don't report variables whose names start with `_`.
+    fn check_usage(&self, scope: &[RustyAsmPiece], lints: &LintLevels, errors: &mut Vec<parse::Error>) {
+        let name = self.ident.to_string();
+        if name.starts_with('_') {
+            return;
+        }
+
+        let mut usage = VarUsage::default();
+        usage.scan(scope, &name);
+        if !usage.used {
+            lints.emit(Lint::UnusedBridgeVar, self.ident.span(), "bridge variable not used",
+                self.constraint_string.span(), "it isn't referenced in any `asm` block in its scope", errors);
+        } else if usage.written && !usage.read {
+            if let ConstraintKeyword::Out | ConstraintKeyword::InOut = self.constraint_keyword {
+                lints.emit(Lint::UnreadOutput, self.ident.span(), "value written to output is never read",
+                    self.constraint_string.span(), "if the register just needs to be reserved, use `clobber` instead", errors);
+            }
+        }
+    }
+
+    // Two inputs (or two outputs) can't be in the same register at the same time, even if they use different names for
+    // it, like `al` and `eax`. (An input and an output can share a register, though.)
+    fn check_register_conflicts(&self, bridge_vars_out: &[BridgeVar], bridge_vars_in: &[BridgeVar],
+            errors: &mut Vec<parse::Error>) {
+        let mut others = Vec::new();
+        if let ConstraintKeyword::Out | ConstraintKeyword::InOut = self.constraint_keyword {
+            others.extend(bridge_vars_out.iter().map(|var| ("output", var)));
+        }
+        if let ConstraintKeyword::In | ConstraintKeyword::InOut | ConstraintKeyword::SliceIn = self.constraint_keyword {
+            others.extend(bridge_vars_in.iter().map(|var| ("input", var)));
+        }
+
+        for constraint_string in self.constraint_strings() {
+            let constraint = constraint_string.value();
+            let reg = match registers::explicit_register(&constraint) {
+                Some(reg) => reg,
+                None => continue
+            };
+            for &(kind, other) in others.iter() {
+                if other.ident == self.ident {
+                    continue; // This declaration shadows it.
+                }
+                if !Condition::may_hold_together(self.attrs.cfg.as_ref(), other.cfg.as_ref()) {
+                    continue; // They're never in the same configuration.
+                }
+                if let Some(other_reg) = other.register(bridge_vars_out) {
+                    if registers::overlap(reg, other_reg) {
+                        errors.push(parse::Error::new(constraint_string.span(), format!(
+                            "register `{}` overlaps register `{}`, which is already bound to {} `{}`",
+                            reg, other_reg, kind, other.name()
+                        )));
+                    }
                 }
-                Self::push_var(bridge_vars_in, BridgeVar {
-                    ident: self.ident.clone(),
-                    llvm_constraint: (format!("{}", index), span) // Linked to the output constraint for the same variable
-                });
             }
         }
     }
 
+    // A flag output is a `bool` that's bound to a single condition code that exists on at least one target.
+    fn check_flag(constraint_keyword: &ConstraintKeyword, explicit_type: &Option<(Token![:], Type)>,
+            condition: &LitStr, second_constraint_string: &Option<LitStr>) -> parse::Result<()> {
+        if !matches!(constraint_keyword, ConstraintKeyword::Out) {
+            return Err(parse::Error::new(condition.span(), "a flag can only be bound with `out`"));
+        }
+        if let Some(ref second) = second_constraint_string {
+            return Err(parse::Error::new(second.span(), "a flag output can only test one condition"));
+        }
+        if let Some((_, ref ty)) = explicit_type {
+            if !is_bool(ty) {
+                return Err(parse::Error::new_spanned(ty, "a flag output has to be a `bool`"));
+            }
+        }
+        if !registers::FLAG_TARGETS.iter().any(|target| target.supports(&condition.value())) {
+            return Err(parse::Error::new(condition.span(), format!("unknown condition code `{}`", condition.value())));
+        }
+        Ok(())
+    }
+
+    // A variable in a register class (or in an explicit register that belongs to one) has to have a type that fits.
+    // Only a named class requires an explicit type, though.
+    fn check_register_class_type(register_class: Option<&RegisterClass>, explicit_type: Option<&Type>,
+            constraint_string: &LitStr) -> parse::Result<()> {
+        let constraint = constraint_string.value();
+        let register = registers::explicit_register(&constraint);
+        let class = match register_class.or_else(|| register.and_then(RegisterClass::of_register)) {
+            Some(class) if !class.types.is_empty() => class,
+            _ => return Ok(())
+        };
+        let target = match register {
+            Some(register) => format!("register `{}`", register),
+            None => format!("`{}`", class.name)
+        };
+
+        let ty = match explicit_type {
+            Some(ty) => ty,
+            None if register.is_some() => return Ok(()),
+            None => return Err(error_with_help(constraint_string.span(),
+                format!("a bridge variable in {} needs an explicit type", target),
+                &format!("use one of {}", class.type_list())
+            ))
+        };
+        let type_name = match ty {
+            Type::Path(path) if path.qself.is_none() => path.path.segments.last()
+                .filter(|segment| segment.value().arguments.is_empty())
+                .map(|segment| segment.value().ident.to_string()),
+            _ => None
+        };
+        if !type_name.map_or(false, |type_name| class.fits(&type_name)) {
+            return Err(error_with_help(ty.span(),
+                format!("type `{}` doesn't fit in {}", quote!(#ty).to_string().replace(' ', ""), target),
+                &format!("use one of {}", class.type_list())
+            ));
+        }
+        Ok(())
+    }
+
+    // The Rust type and the register type, if the variable is converted between them
+    fn conversion(&self) -> Option<(Type, Type)> {
+        match (&self.explicit_type, &self.register_type) {
+            (Some((_, rust_type)), Some((_, register_type))) => Some((rust_type.clone(), register_type.clone())),
+            _ => None
+        }
+    }
+
+    // Checks at compile time that the `AsmOperand` implementation of the variable's type allows the register class that
+    // the variable is bound to, if we know what that is. The check is a statement rather than an item so that the type
+    // can use the function's generic parameters.
+    fn operand_class_assertion(&self, rust_type: &Type) -> TokenStream {
+        let class = match self.register_class.or_else(|| RegisterClass::of_constraint(&self.constraint_string.value())) {
+            Some(class) => class,
+            None => return TokenStream::new()
+        };
+        // `rusty_asm_runtime::ClassCheck` names its constants after the variants of `RegisterClass`, as in `XMM_REG`.
+        let mut check = String::new();
+        for (i, c) in class.runtime_name.chars().enumerate() {
+            if i > 0 && c.is_ascii_uppercase() {
+                check.push('_');
+            }
+            check.push(c.to_ascii_uppercase());
+        }
+        let check = Ident::new(&check, Span::call_site());
+        quote_spanned!(self.constraint_string.span()=>
+            let _ = ::rusty_asm_runtime::ClassCheck::<#rust_type>::#check;
+        )
+    }
+
+    // The register class that the constraint refers to, either by name or by naming one of its registers
+    fn class(&self, constraint_string: &LitStr) -> Option<&'static RegisterClass> {
+        self.register_class.or_else(|| {
+            registers::explicit_register(&constraint_string.value()).and_then(RegisterClass::of_register)
+        })
+    }
+
+    // Every constraint in the declaration: one for most variables, or two for a slice or a wide variable
+    fn constraint_strings(&self) -> impl Iterator<Item = &LitStr> {
+        Some(&self.constraint_string).into_iter().chain(self.second_constraint_string.as_ref())
+    }
+
     fn push_var(vec: &mut Vec<BridgeVar>, var: BridgeVar) -> Option<usize> {
         // First, check for a duplicate and overwrite it if it's found.
         // TODO: It might be worthwhile to use a HashSet to make finding duplicates faster.
@@ -347,27 +964,144 @@ impl BridgeVarDecl {
         None
     }
 
-    // Using swap_remove is O(1). It doesn't preserve the order of the elements, but we don't always care about that.
-    // Specifically, we don't care about it with the input and clobber vectors. And removing from the output vector
-    // would require special handling anyway to make sure we don't break any `inout` constraints.
-    fn swap_remove_var(vec: &mut Vec<BridgeVar>, var: BridgeVar) {
-        // TODO: This search, on the other hand, is O(n). HashSet?
-        let mut index = vec.len();
-        for (i, ref other) in vec.iter().enumerate() {
-            if var.bad_duplicate_of(other) {
-                index = i;
-                break;
+    // Removes the output at the given index, along with its tied input, if there is one. The remaining operands keep
+    // their order, so that the operand numbering in the generated `asm!` invocation only ever depends on the order of the
+    // declarations. Inputs that are tied to later outputs are renumbered to match.
+    fn remove_output(bridge_vars_out: &mut Vec<BridgeVar>, bridge_vars_in: &mut Vec<BridgeVar>, index: usize) {
+        bridge_vars_out.remove(index);
+        bridge_vars_in.retain(|var| var.constraint_as_str() != index.to_string());
+        for var in bridge_vars_in.iter_mut() {
+            if let Ok(tied_index) = var.constraint_as_str().parse::<usize>() {
+                if tied_index > index {
+                    var.llvm_constraint.0 = (tied_index - 1).to_string();
+                }
             }
         }
-        if index < vec.len() {
-            vec.swap_remove(index);
+    }
+}
+
+// What happens to a bridge variable within its scope
+#[derive(Debug, Default)]
+struct VarUsage {
+    // An `asm` block references it or binds it to an explicit register.
+    used: bool,
+    // An `asm` block writes to it.
+    written: bool,
+    // Rust code or another `asm` block reads it after it's been written. (Code before the `asm` block counts if they're
+    // both in a loop, since it runs again after the block.)
+    read: bool
+}
+
+impl VarUsage {
+    // Scans the pieces of code that can see the variable with the given name. Returns `false` if it gets shadowed by
+    // another variable, which ends its scope.
+    fn scan(&mut self, pieces: &[RustyAsmPiece], name: &str) -> bool {
+        // A plain Rust `let` statement whose pattern binds the name ends the variable's scope too, but only after the
+        // initializer, which can still see it.
+        let mut pattern: Option<Vec<TokenTree>> = None;
+        let mut shadowed = false;
+        // The tokens before a block that make it the body of a loop, like `while i < 10`
+        let mut loop_header: Option<Vec<TokenTree>> = None;
+        for piece in pieces {
+            match piece {
+                // A struct pattern's braces look like a block.
+                RustyAsmPiece::RustyAsmBlock(..) if pattern.is_some() => {
+                    pattern.as_mut().unwrap().extend(piece.into_token_stream());
+                },
+                RustyAsmPiece::RustyAsmBlock(_, block) => {
+                    // Shadowing in an inner block doesn't affect the rest of this block.
+                    self.scan(&block.contents, name);
+                    if let Some(header) = loop_header.take() {
+                        // A loop runs its header and body again, after the body may have written to the variable.
+                        self.read |= self.written && mentions(header.into_iter().collect(), name);
+                        self.scan(&block.contents, name);
+                    }
+                },
+                RustyAsmPiece::BridgeVarDecl(decl) => {
+                    if let Some((_, ref init_expr)) = decl.assignment {
+                        self.read |= self.written && mentions(init_expr.into_token_stream(), name);
+                    }
+                    // A declaration that only exists in some configurations doesn't always shadow it.
+                    if decl.ident == name && decl.attrs.cfg.is_none() {
+                        return false;
+                    }
+                },
+                RustyAsmPiece::ClobberDecl(_) => {},
+                RustyAsmPiece::AsmBlock(block) => {
+                    // Only one alternative is compiled for any given target, but we don't know which one.
+                    let written = self.written;
+                    for alternative in block.chain().into_iter().filter(|alternative| !alternative.declares_var(name)) {
+                        self.used |= alternative.uses_var(name);
+                        self.read |= written && alternative.reads_var(name);
+                        self.written |= alternative.writes_var(name);
+                        // Expressions in the ASM code are Rust code, too, and they're evaluated first.
+                        self.read |= written && alternative.all_inline_operands().iter()
+                            .any(|operand| mentions((&operand.expr).into_token_stream(), name));
+                        // The labels' code runs after the ASM code.
+                        self.read |= (written || alternative.writes_var(name))
+                            && alternative.labels.iter().any(|arm| mentions(arm.tts.clone(), name));
+                    }
+                    for (_, tts) in block.rust_alternative().into_iter().chain(block.fallback()) {
+                        self.read |= written && mentions(tts.clone(), name);
+                    }
+                },
+                RustyAsmPiece::TokenTrees(tts) => {
+                    let end_of_pattern = match tts.as_slice() {
+                        [TokenTree::Punct(punct)] => match punct.as_char() {
+                            '=' | ';' => true,
+                            // A type comes after the pattern, but a path in the pattern has `::` in it.
+                            ':' => punct.spacing() == Spacing::Alone && !pattern.as_ref()
+                                .and_then(|pattern| pattern.last())
+                                .map_or(false, |tt| match tt {
+                                    TokenTree::Punct(punct) => punct.as_char() == ':',
+                                    _ => false
+                                }),
+                            _ => false
+                        },
+                        _ => false
+                    };
+                    match pattern.take() {
+                        Some(tokens) if end_of_pattern => shadowed |= binds(tokens.into_iter().collect(), name),
+                        Some(mut tokens) => {
+                            tokens.extend(tts.iter().cloned());
+                            pattern = Some(tokens);
+                            continue;
+                        },
+                        None => {}
+                    }
+
+                    match tts.first() {
+                        Some(TokenTree::Ident(ident)) if tts.len() == 1 && ident == "let" => {
+                            pattern = Some(Vec::new());
+                            continue;
+                        },
+                        Some(TokenTree::Ident(ident)) if ident == "loop" || ident == "while" || ident == "for" => {
+                            loop_header = Some(Vec::new());
+                        },
+                        Some(TokenTree::Punct(punct)) if tts.len() == 1 && punct.as_char() == ';' => {
+                            if shadowed {
+                                return false;
+                            }
+                            loop_header = None;
+                        },
+                        _ => {}
+                    }
+                    if let Some(ref mut header) = loop_header {
+                        header.extend(tts.iter().cloned());
+                    }
+                    self.read |= self.written && mentions(tts.iter().cloned().collect(), name);
+                }
+            }
         }
+        true
     }
 }
 
 #[derive(Debug, Clone)]
 struct ClobberDecl {
-    constraint_string: LitStr
+    constraint_string: LitStr,
+    // The condition from any `#[cfg]` attributes
+    cfg: Option<Condition>
 }
 
 impl Parse for ClobberDecl {
@@ -376,164 +1110,1309 @@ impl Parse for ClobberDecl {
         let content;
         parenthesized!(content in input);
         let constraint_string = content.parse::<LitStr>()?;
+        expect_end(&content)?;
         input.parse::<Token![;]>()?;
-        
-        Ok(ClobberDecl { constraint_string })
+
+        Ok(ClobberDecl { constraint_string, cfg: None })
     }
 }
 
 impl ToTokens for ClobberDecl {
-    fn to_tokens(&self, _: &mut TokenStream) {
-        // We have nothing to do here. A clobber doesn't correspond to any Rust statements.
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        // A clobber doesn't correspond to any Rust statements. We only have to check that the register can be used.
+        let constraint = self.constraint_string.value();
+        let reg = registers::explicit_register(&constraint).unwrap_or(&constraint);
+        let errors = reserved_register_errors(&self.constraint_string, reg,
+            "save the register's value and restore it in the ASM code instead");
+        match self.cfg {
+            Some(ref cfg) if !errors.is_empty() => tokens.append_all(quote!(#[cfg(#cfg)] { #errors })),
+            _ => tokens.append_all(errors)
+        }
     }
 }
 
 impl ClobberDecl {
-    fn push_clobber(&self, clobbers: &mut HashSet<Clobber>) {
-        clobbers.insert(Clobber {
-            llvm_constraint: (self.constraint_string.value(), self.constraint_string.span())
-        });
+    // Determines whether the upcoming tokens are meant to be a clobber declaration, even if they aren't a valid one.
+    fn peek(input: ParseStream) -> bool {
+        input.peek(keyword::clobber) && input.peek2(Paren)
+    }
+
+    fn push_clobber(&self, clobbers: &mut Vec<Clobber>) {
+        // Clobbers are kept in declaration order. A repeated clobber keeps the position of its first declaration, and it
+        // exists in every configuration where either declaration does.
+        let clobber = Clobber {
+            llvm_constraint: (self.constraint_string.value(), self.constraint_string.span()),
+            cfg: self.cfg.clone()
+        };
+        match clobbers.iter_mut().find(|other| **other == clobber) {
+            Some(other) => other.cfg = match (other.cfg.take(), clobber.cfg) {
+                (Some(a), Some(b)) => Some(Condition::Any(vec![a, b])),
+                _ => None
+            },
+            None => clobbers.push(clobber)
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 struct AsmBlock {
-    options: Punctuated<LitStr, Token![,]>,
+    asm_keyword: keyword::asm,
+    options: Punctuated<AsmOption, Token![,]>,
+    // The outputs that the block evaluates to, as in `asm -> (out("{eax}"), out("{edx}")) {...}`
+    yields: Option<Yields>,
     asm_unchanged: Option<LitStr>,
+    // What to do on targets that this block isn't for, if it has a `target_arch`: either another `asm` block or Rust
+    // code (as in `asm(target_arch = "x86") {...} else asm(target_arch = "aarch64") {...} else {...}`)
+    else_branch: Option<ElseBranch>,
+    // Rust code to use instead of the ASM code where it can't run, as in `asm {...} fallback {...}`
+    fallback: Option<(keyword::fallback, Brace, TokenStream)>,
+    // The Rust code that runs after the ASM code if it takes a label, as in `asm { "jz $on_zero" } on_zero => {...}`
+    labels: Vec<LabelArm>,
 
     bridge_vars_out: Vec<BridgeVar>,
     bridge_vars_in: Vec<BridgeVar>,
-    clobbers: HashSet<Clobber>
+    clobbers: Vec<Clobber>,
+    lints: LintLevels,
+
+    // The ASM code in LLVM's format, and the span of the first reference to each bridge variable in it
+    llvm_asm: Option<LitStr>,
+    used_idents: HashMap<String, Span>,
+    // The operands that the ASM code gives as Rust expressions, which are also at the end of `bridge_vars_in`
+    inline_operands: Vec<InlineOperand>,
+
+    // The block's outer attributes, like `#[cfg(...)]`
+    attrs: OuterAttributes,
+    // If some of the operands or clobbers only exist in some configurations, a copy of the block for each group of
+    // configurations that has the same ones. These are finished instead of the block itself.
+    variants: Vec<Variant>
+}
+
+// `<label> => {...}` after an `asm` block. The ASM code takes it by jumping to a stub named `$<label>`, which records it in
+// an output, and the Rust code runs right after the block.
+#[derive(Debug, Clone)]
+struct LabelArm {
+    label: Ident,
+    brace: Brace,
+    tts: TokenStream
+}
+
+// The output that tells the Rust code which label the ASM code took
+const LABEL_VAR: &str = "__rusty_asm_label";
+
+// The start of the name of an output that an `asm` block yields without naming it, which is followed by its index
+const YIELD_VAR_PREFIX: &str = "__rusty_asm_yield_";
+
+// A copy of an `asm` block with only the operands and clobbers that exist in some of the configurations
+#[derive(Debug, Clone)]
+struct Variant {
+    // The configurations, unless the variant is the only one
+    cfg: Option<Condition>,
+    block: AsmBlock,
+    // Any errors in the variant, which only matter if it's compiled
+    errors: TokenStream
 }
 
 impl AsmBlock {
     fn parse(input: ParseStream, bridge_vars_out: Vec<BridgeVar>, bridge_vars_in: Vec<BridgeVar>,
-            clobbers: HashSet<Clobber>) -> parse::Result<Self> {
-        input.parse::<keyword::asm>()?;
+            clobbers: Vec<Clobber>, mut lints: LintLevels) -> parse::Result<Self> {
+        let asm_keyword = input.parse::<keyword::asm>()?;
+        // Alternatives don't inherit this block's lint options.
+        let outer_lints = lints.clone();
 
-        let options: Punctuated<LitStr, Token![,]>;
+        let options: Punctuated<AsmOption, Token![,]>;
         if let Ok(content) = parenthesized(input) {
             if content.is_empty() {
                 options = Punctuated::new();
             } else {
                 options = content.call(Punctuated::parse_separated_nonempty)?;
+                expect_end(&content)?;
             }
         } else {
             options = Punctuated::new();
         }
+        // `-> <output>` or `-> (<output>, ...)`
+        let yields = if input.peek(Token![->]) {
+            Some(input.parse::<Yields>()?)
+        } else {
+            None
+        };
+        let mut memory_option = None::<&Ident>;
+        for option in options.iter() {
+            match *option {
+                AsmOption::Lint(level, ref names) => {
+                    for name in names.iter() {
+                        if !lints.set(name, level) {
+                            return Err(parse::Error::new(name.span(), format!("unknown lint `{}`", name)));
+                        }
+                    }
+                },
+                AsmOption::Memory(ref ident) => {
+                    if let Some(other) = memory_option {
+                        return Err(parse::Error::new(ident.span(),
+                            format!("`{}` can't be used together with `{}`", ident, other)));
+                    }
+                    memory_option = Some(ident);
+                },
+                AsmOption::Llvm(_) | AsmOption::TargetArch(_) | AsmOption::Checked | AsmOption::Trace(..)
+                    | AsmOption::NoReturn | AsmOption::Operand(_) => {}
+            }
+        }
 
         let content;
         braced!(content in input);
-        let asm_unchanged = content.parse::<LitStr>().ok();
+        let asm_unchanged;
+        if content.is_empty() {
+            asm_unchanged = None;
+        } else {
+            asm_unchanged = Some(content.parse::<LitStr>()?);
+            expect_end(&content)?;
+        }
+
+        // `label => {...}`
+        let mut labels = Vec::new();
+        while input.peek(Ident) && input.peek2(Token![=>]) {
+            let label = input.parse::<Ident>()?;
+            input.parse::<Token![=>]>()?;
+            let content;
+            let brace = braced!(content in input);
+            labels.push(LabelArm { label, brace, tts: content.parse()? });
+        }
+
+        // `else asm(...) {...}` or `else {...}`
+        let else_branch = if input.peek(Token![else]) {
+            input.parse::<Token![else]>()?;
+            if input.peek(Brace) {
+                let content;
+                let brace = braced!(content in input);
+                Some(ElseBranch::Rust(brace, content.parse()?))
+            } else {
+                let block = AsmBlock::parse(input, bridge_vars_out.clone(), bridge_vars_in.clone(), clobbers.clone(),
+                    outer_lints)?;
+                Some(ElseBranch::Asm(Box::new(block)))
+            }
+        } else {
+            None
+        };
+
+        // `fallback {...}`, which ends the chain
+        let fallback = if input.peek(keyword::fallback) && input.peek2(Brace) {
+            let keyword = input.parse::<keyword::fallback>()?;
+            let content;
+            let brace = braced!(content in input);
+            Some((keyword, brace, content.parse()?))
+        } else {
+            None
+        };
 
         Ok(AsmBlock {
+            asm_keyword,
             options,
+            yields,
             asm_unchanged,
+            else_branch,
+            fallback,
+            labels,
 
             bridge_vars_out,
             bridge_vars_in,
-            clobbers
+            clobbers,
+            lints,
+
+            llvm_asm: None,
+            used_idents: HashMap::new(),
+            inline_operands: Vec::new(),
+
+            attrs: OuterAttributes::default(),
+            variants: Vec::new()
         })
     }
 }
 
+impl AsmBlock {
+    // Determines whether the upcoming tokens are meant to be an `asm` block, even if they aren't a valid one.
+    fn peek(input: ParseStream) -> bool {
+        input.peek(keyword::asm) && (input.peek2(Brace) || input.peek2(Token![->])
+            || (input.peek2(Paren) && (input.peek3(Brace) || input.peek3(Token![->]))))
+    }
+}
+
 impl ToTokens for AsmBlock {
     fn to_tokens(&self, tokens: &mut TokenStream) {
-        // Emit a standard (albeit unstable) `asm!` macro.
+        // The `fallback` replaces the whole chain under Miri and on targets without inline ASM, or everywhere with the
+        // `force-fallback` feature.
+        let mut code = TokenStream::new();
+        match self.fallback() {
+            Some((brace, tts)) if cfg!(feature = "force-fallback") => {
+                code.append_all(self.rust_block_to_tokens(brace, tts));
+            },
+            Some((brace, tts)) => {
+                let mut chain = TokenStream::new();
+                self.chain_to_tokens(&mut chain);
+                let fallback = self.rust_block_to_tokens(brace, tts);
+                let inline_asm_targets = &registers::INLINE_ASM_TARGETS;
+                code.append_all(quote!(#[cfg(not(any(miri, not(any(#(target_arch = #inline_asm_targets),*)))))] {
+                    #chain
+                }));
+                code.append_all(quote_spanned!(brace.span=>
+                    #[cfg(any(miri, not(any(#(target_arch = #inline_asm_targets),*))))] #fallback
+                ));
+            },
+            None => self.chain_to_tokens(&mut code)
+        }
 
-        if let Some(ref asm_unchanged) = self.asm_unchanged {
-            let asm_span = asm_unchanged.span();
+        // If the chain yields outputs, whichever part of it is compiled stores them in the result.
+        if self.yields.is_some() {
+            tokens.append_all(quote!({
+                let __rusty_asm_result;
+                #code
+                __rusty_asm_result
+            }));
+        } else if !self.attrs.is_empty() {
+            let attrs = &self.attrs;
+            tokens.append_all(quote!(#attrs { #code }));
+        } else if self.chain().iter().any(|alternative| alternative.is_noreturn()) {
+            // A block that never finishes is an expression of type `!`, so it has to be one expression.
+            tokens.append_all(quote!({ #code }));
+        } else {
+            tokens.append_all(code);
+        }
+    }
+}
 
-            // Replace every occurrence of `$<ident>` in the ASM code with the appropriate `$0`, `$1`, etc.
-            let (llvm_asm, used_idents) = self.replace_identifiers(asm_unchanged.value().as_str(), asm_span);
+impl AsmBlock {
+    // Emits this block and its alternatives, without the `fallback`.
+    fn chain_to_tokens(&self, tokens: &mut TokenStream) {
+        if self.else_branch.is_none() && self.target_arch().is_none() {
+            self.alternative_to_tokens(tokens);
+            return;
+        }
 
-            // Warn the programmer if one of the available bridge variables wasn't referenced in the ASM code.
-            for var in self.bridge_vars_out.iter().chain(self.bridge_vars_in.iter()) {
-                if !used_idents.contains(&var.ident.to_string()) {
-                    warn(var.ident.span(), "bridge variable not used");
-                    help(asm_span, "in this `asm` block");
-                }
+        // Each alternative is compiled only for its own target, and the `else` block (if any) for every other target.
+        let mut target_archs = Vec::new();
+        for alternative in self.chain() {
+            // A missing `target_arch` has already been reported.
+            if let Some(target_arch) = alternative.target_arch() {
+                let mut invocation = TokenStream::new();
+                alternative.alternative_to_tokens(&mut invocation);
+                tokens.append_all(quote!(#[cfg(target_arch = #target_arch)] { #invocation }));
+                target_archs.push(target_arch);
+            }
+        }
+        match self.rust_alternative() {
+            Some((brace, tts)) => {
+                let rust_block = self.rust_block_to_tokens(brace, tts);
+                tokens.append_all(quote_spanned!(brace.span=>
+                    #[cfg(not(any(#(target_arch = #target_archs),*)))] #rust_block
+                ));
+            },
+            None => {
+                let message = format!("this `asm` block has no alternative for this target{}",
+                    diagnostics::help_suffix("add `else { ... }` with Rust code to run instead"));
+                tokens.append_all(quote_spanned!(self.asm_keyword.span=>
+                    #[cfg(not(any(#(target_arch = #target_archs),*)))]
+                    compile_error!(#message);
+                ));
             }
-
-            let asm_str = LitStr::new(llvm_asm.as_str(), asm_span);
-            let constraints_out = self.bridge_vars_out.iter().map(|v| v.constraint_as_tokens());
-            let constraints_in = self.bridge_vars_in.iter().map(|v| v.constraint_as_tokens());
-            let constraints_clobber = self.clobbers.iter().map(|v| v.constraint_as_lit_str());
-            let options = &self.options;
-
-            let temp_tokens = quote!(asm!(#asm_str : #(#constraints_out),* : #(#constraints_in),* : #(#constraints_clobber),* : #(#options),*););
-            tokens.append_all(temp_tokens);
         }
     }
 }
 
 impl AsmBlock {
-    // Replaces every occurrence of `$<ident>` in `orig` with the appropriate numeral reference to an
-    // input or output register, if the identifier matches a bridge variable.
-    fn replace_identifiers(&self, orig: &str, span: Span) -> (String, HashSet<String>) {
-        let mut result = String::new();
-        let mut used_idents = HashSet::new();
+    // Emits the code for this block alone. Its own operands are declared first, and any outputs that it yields are
+    // stored in the chain's result afterward.
+    fn alternative_to_tokens(&self, tokens: &mut TokenStream) {
+        let decls = self.own_operands().collect::<Vec<_>>();
+        // `asm!` has no way to say that the ASM code never finishes, so we tell the compiler ourselves.
+        let noreturn = if self.is_noreturn() {
+            Some(quote_spanned!(self.asm_keyword.span=> ::core::hint::unreachable_unchecked()))
+        } else {
+            None
+        };
+        // The Rust code for the label that the ASM code jumped to, if any, runs right after it.
+        let (label_var, labels) = if self.labels.is_empty() {
+            (None, None)
+        } else {
+            let label_var = Ident::new(LABEL_VAR, Span::call_site());
+            let values = (1 ..= self.labels.len()).map(proc_macro2::Literal::usize_unsuffixed);
+            let arms = self.labels.iter().map(|arm| {
+                let tts = &arm.tts;
+                quote_spanned!(arm.brace.span=> { #tts })
+            });
+            (Some(quote!(let #label_var: usize;)), Some(quote!(match #label_var { #(#values => #arms)* _ => {} })))
+        };
+        if decls.is_empty() && noreturn.is_none() && labels.is_none() {
+            self.invocation_to_tokens(tokens);
+            return;
+        }
+
+        let mut invocation = TokenStream::new();
+        self.invocation_to_tokens(&mut invocation);
+        let result = self.yields.as_ref().map(|yields| {
+            let value = yields.value();
+            quote!(__rusty_asm_result = #value;)
+        });
+        tokens.append_all(quote!({
+            #(#decls)*
+            #label_var
+            #invocation
+            #labels
+            #result
+            #noreturn
+        }));
+    }
+
+    // Emits a block of Rust code from the chain. If the chain yields outputs, the block's value is stored in the result.
+    fn rust_block_to_tokens(&self, brace: &Brace, tts: &TokenStream) -> TokenStream {
+        if self.yields.is_some() {
+            quote_spanned!(brace.span=> { __rusty_asm_result = { #tts }; })
+        } else {
+            quote_spanned!(brace.span=> { #tts })
+        }
+    }
+
+    // Emits a standard (albeit unstable) `asm!` macro for this block alone, ignoring its alternatives.
+    fn invocation_to_tokens(&self, tokens: &mut TokenStream) {
+        for variant in self.variants.iter() {
+            let mut invocation = TokenStream::new();
+            variant.block.invocation_to_tokens(&mut invocation);
+            match variant.cfg {
+                Some(ref cfg) => {
+                    let errors = &variant.errors;
+                    tokens.append_all(quote!(#[cfg(#cfg)] { #errors #invocation }));
+                },
+                None => tokens.append_all(invocation)
+            }
+        }
+
+        if let Some(ref asm_str) = self.llvm_asm {
+            // Operands that are referenced in the ASM code get the span of the (first) reference, so that tools like
+            // IDEs can link it to the variable's declaration.
+            let operand = |var: &BridgeVar| {
+                var.constraint_as_tokens(self.used_idents.get(&var.name()).cloned())
+            };
+
+            let constraints_in = self.bridge_vars_in.iter().map(operand).collect::<Vec<_>>();
+            let constraints_clobber = self.clobbers.iter().map(|v| v.constraint_as_lit_str()).collect::<Vec<_>>();
+            let options = self.options.iter().filter_map(AsmOption::llvm_option).collect::<Vec<_>>();
+            // `chosen_outputs` lists the outputs that the compiler puts in general-purpose registers of its choice.
+            let invocation = |asm_str: &LitStr, constraints_out: Vec<TokenStream>, chosen_outputs: Vec<usize>| {
+                let plain = {
+                    let (constraints_out, constraints_in, constraints_clobber, options) =
+                        (constraints_out.iter(), constraints_in.iter(), constraints_clobber.iter(), options.iter());
+                    quote!(asm!(#asm_str : #(#constraints_out),* : #(#constraints_in),* : #(#constraints_clobber),* :
+                        #(#options),*);)
+                };
+                if self.is_checked() {
+                    let checked = self.checked_invocations(asm_str, &constraints_out, &constraints_in,
+                        &constraints_clobber, &options, &chosen_outputs);
+                    let target_archs = registers::CHECKED_TARGETS.iter().map(|target| target.target_arch);
+                    quote!({
+                        #[cfg(not(all(debug_assertions, any(#(target_arch = #target_archs),*))))] #plain
+                        #checked
+                    })
+                } else {
+                    plain
+                }
+            };
+            let chosen_outputs = |include_flags: bool| self.bridge_vars_out.iter().enumerate()
+                .filter(|&(_, var)| var.in_chosen_register() || (include_flags && var.flag_condition.is_some()))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+
+            let mut temps = Vec::new();
+            let mut reassembly = Vec::new();
+
+            // Flag outputs use LLVM's flag output constraints if the `flag-outputs` feature says the compiler has them.
+            // Otherwise, the ASM code is extended with an instruction that copies the flag to a temporary register, and
+            // since that instruction depends on the target, there's a separate `asm!` invocation for each target.
+            let flag_outputs = self.bridge_vars_out.iter().enumerate()
+                .filter_map(|(i, var)| var.flag_condition.as_ref().map(|condition| (i, var, condition)))
+                .collect::<Vec<_>>();
+            let asm = if flag_outputs.is_empty() || cfg!(feature = "flag-outputs") {
+                invocation(asm_str, self.bridge_vars_out.iter().map(operand).collect(), chosen_outputs(false))
+            } else {
+                for &(_, var, _) in flag_outputs.iter() {
+                    let (ident, temp) = (&var.ident, var.temp_ident());
+                    temps.push(quote!(let #temp: u8;));
+                    reassembly.push(quote!(#ident = #temp != 0;));
+                }
+
+                let mut asm = TokenStream::new();
+                // Targets that don't support a condition code get an error from the declaration instead.
+                let targets = registers::FLAG_TARGETS.iter()
+                    .filter(|target| flag_outputs.iter().all(|&(_, _, condition)| target.supports(condition)));
+                for target in targets {
+                    let mut target_asm = asm_str.value();
+                    for &(i, _, condition) in flag_outputs.iter() {
+                        target_asm.push_str("\n\t");
+                        target_asm.push_str(&target.fallback_instruction(condition, i));
+                    }
+                    let target_asm = LitStr::new(&target_asm, asm_str.span());
+                    let constraints_out = self.bridge_vars_out.iter().map(|var| {
+                        if var.flag_condition.is_some() {
+                            let constraint = LitStr::new(target.fallback_constraint, var.constraint_span());
+                            let temp = var.temp_ident();
+                            quote!(#constraint(#temp))
+                        } else {
+                            operand(var)
+                        }
+                    }).collect();
+                    let target_archs = target.target_archs;
+                    let target_invocation = invocation(&target_asm, constraints_out, chosen_outputs(true));
+                    asm.append_all(quote!(#[cfg(any(#(target_arch = #target_archs),*))] #target_invocation));
+                }
+                asm
+            };
+
+            // Wide outputs are written to temporary variables, one for each half, and then reassembled.
+            let wide_outputs = self.bridge_vars_out.iter().filter_map(|var| match var.part {
+                Some(Part::Lo(wide_type)) => {
+                    let hi = self.bridge_vars_out.iter().find(|hi| hi.ident == var.ident && hi.part == Some(Part::Hi(wide_type)))?;
+                    Some((var, hi, wide_type))
+                },
+                _ => None
+            });
+            for (lo, hi, wide_type) in wide_outputs {
+                let ident = &lo.ident;
+                let (lo, hi, half) = (lo.temp_ident(), hi.temp_ident(), wide_type.half());
+                let (name, unsigned, bits) = (Ident::new(wide_type.name(), Span::call_site()), wide_type.unsigned(),
+                    wide_type.half_bits());
+                temps.push(quote!(let #lo: #half; let #hi: #half;));
+                reassembly.push(quote!(#ident = (((#hi as #unsigned) << #bits) | (#lo as #unsigned)) as #name;));
+            }
+
+            // Converted outputs are written to temporary variables of their register types and then converted back.
+            // A `bool` is converted by value, so any nonzero value is `true`. Anything else is transmuted, so the ASM
+            // code has to leave a valid value in the register (which isn't checked), unless the register type is `_`
+            // and the type's `AsmOperand` implementation takes care of it.
+            for var in self.bridge_vars_out.iter() {
+                if let Some((ref rust_type, ref register_type)) = var.conversion {
+                    let (ident, temp) = (&var.ident, var.temp_ident());
+                    if let Type::Infer(_) = register_type {
+                        temps.push(quote!(let #temp: <#rust_type as ::rusty_asm_runtime::AsmOperand>::Register;));
+                        reassembly.push(quote!(
+                            #ident = <#rust_type as ::rusty_asm_runtime::AsmOperand>::from_register(#temp);
+                        ));
+                        continue;
+                    }
+                    temps.push(quote!(let #temp: #register_type;));
+                    if is_bool(rust_type) {
+                        reassembly.push(quote!(#ident = #temp != 0;));
+                    } else {
+                        reassembly.push(quote!(#ident = ::core::mem::transmute::<#register_type, #rust_type>(#temp);));
+                    }
+                }
+            }
+
+            // Operands that are given as Rust expressions are evaluated first, in the order they appear.
+            let mut bindings = Vec::new();
+            for operand in self.inline_operands.iter() {
+                let (ident, expr) = (&operand.var.ident, &operand.expr);
+                bindings.push(register_class_errors(operand.class, operand.var.constraint_span()));
+                bindings.push(quote!(let #ident = #expr;));
+            }
+
+            let trace = self.options.iter().find_map(|option| match option {
+                AsmOption::Trace(keyword, hook) => Some((keyword, hook.as_ref())),
+                _ => None
+            });
+            if let Some((keyword, hook)) = trace {
+                // Inputs are traced before the ASM code runs and outputs after they've been reassembled, once for each
+                // variable.
+                let trace_in = self.trace_calls(keyword, hook, "in", &self.bridge_vars_in);
+                let trace_out = self.trace_calls(keyword, hook, "out", &self.bridge_vars_out);
+                tokens.append_all(quote!({
+                    #(#bindings)*
+                    #[cfg(debug_assertions)] { #trace_in }
+                    #(#temps)*
+                    #asm
+                    #(#reassembly)*
+                    #[cfg(debug_assertions)] { #trace_out }
+                }));
+            } else if bindings.is_empty() && temps.is_empty() {
+                tokens.append_all(asm);
+            } else {
+                tokens.append_all(quote!({
+                    #(#bindings)*
+                    #(#temps)*
+                    #asm
+                    #(#reassembly)*
+                }));
+            }
+        }
+    }
+
+    // Returns a call to the tracing hook (or `eprintln!` if there isn't one) for each of the variables, labeled with
+    // the location of the `asm` block. The `eprintln!` is spanned at the `trace` keyword, so that's where the error
+    // points if `std` isn't available.
+    fn trace_calls(&self, keyword: &Ident, hook: Option<&Path>, direction: &str, vars: &[BridgeVar]) -> TokenStream {
+        let location = quote_spanned!(self.asm_keyword.span=>
+            ::core::concat!(::core::file!(), ":", ::core::line!(), ":", ::core::column!())
+        );
+        let mut idents = Vec::<&Ident>::new();
+        for var in vars.iter() {
+            if !idents.contains(&&var.ident) {
+                idents.push(&var.ident);
+            }
+        }
+
+        let mut tokens = TokenStream::new();
+        for ident in idents {
+            // An operand that's given as an expression is labeled with the expression.
+            let name = self.inline_operands.iter().find(|operand| operand.var.ident == *ident)
+                .map_or_else(|| ident.to_string(), |operand| operand.source.clone());
+            let name = if name == LABEL_VAR {
+                String::from("label")
+            } else if name.starts_with(YIELD_VAR_PREFIX) {
+                // An unnamed yielded output is labeled by its position in the value.
+                match self.yields {
+                    Some(Yields { tuple: true, .. }) => format!("yield.{}", name.trim_start_matches(YIELD_VAR_PREFIX)),
+                    _ => String::from("yield")
+                }
+            } else {
+                name
+            };
+            tokens.append_all(match hook {
+                Some(hook) => quote!(#hook(#location, #direction, #name, &#ident);),
+                None => {
+                    let format = format!("[{{}}] {} {} = {{:?}}", direction, name);
+                    quote_spanned!(keyword.span()=> ::std::eprintln!(#format, #location, #ident);)
+                }
+            });
+        }
+        tokens
+    }
+}
+
+// The outputs that an `asm` block evaluates to. Each one is declared inline, like a bridge variable that's only in scope
+// in that block.
+#[derive(Debug, Clone)]
+struct Yields {
+    decls: Vec<BridgeVarDecl>,
+    // Whether the value is a tuple, as it is unless there's exactly one output without a trailing comma
+    tuple: bool
+}
+
+impl Parse for Yields {
+    fn parse(input: ParseStream) -> parse::Result<Self> {
+        input.parse::<Token![->]>()?;
+        let mut decls = Vec::new();
+        if input.peek(Paren) {
+            let content;
+            parenthesized!(content in input);
+            let mut trailing_comma = false;
+            while !content.is_empty() {
+                decls.push(Self::parse_output(&content, decls.len())?);
+                trailing_comma = content.parse::<Option<Token![,]>>()?.is_some();
+                if !trailing_comma {
+                    expect_end(&content)?;
+                }
+            }
+            let tuple = decls.len() != 1 || trailing_comma;
+            Ok(Yields { decls, tuple })
+        } else {
+            decls.push(Self::parse_output(input, 0)?);
+            Ok(Yields { decls, tuple: false })
+        }
+    }
+}
+
+impl Yields {
+    // Whether the value is a tuple, and how many outputs it has
+    fn shape(&self) -> (bool, usize) {
+        (self.tuple, self.decls.len())
+    }
+
+    // Describes the shape for error messages.
+    fn describe(&self) -> String {
+        match self.shape() {
+            (false, _) => String::from("a single output"),
+            (true, 1) => String::from("a tuple of 1 output"),
+            (true, count) => format!("a tuple of {} outputs", count)
+        }
+    }
+
+    // An output doesn't need a name unless the ASM code refers to it.
+    fn parse_output(input: ParseStream, index: usize) -> parse::Result<BridgeVarDecl> {
+        let unnamed = Ident::new(&format!("{}{}", YIELD_VAR_PREFIX, index), Span::call_site());
+        let decl = BridgeVarDecl::parse_inline(input, Some(unnamed))?;
+        match decl.constraint_keyword {
+            ConstraintKeyword::Out => Ok(decl),
+            _ => Err(parse::Error::new(decl.constraint_string.span(), "an `asm` block can only yield `out` operands"))
+        }
+    }
+
+    // The expression that the outputs are collected into
+    fn value(&self) -> TokenStream {
+        let idents = self.decls.iter().map(|decl| &decl.ident);
+        if self.tuple {
+            quote!((#(#idents,)*))
+        } else {
+            quote!(#(#idents)*)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ElseBranch {
+    // `else asm(...) {...}`
+    Asm(Box<AsmBlock>),
+    // `else {...}`
+    Rust(Brace, TokenStream)
+}
+
+impl AsmBlock {
+    // Finishes a block after it's been parsed, along with all of its alternatives.
+    fn finish(&mut self, errors: &mut Vec<parse::Error>) {
+        let mut bridge_vars_out = mem::take(&mut self.bridge_vars_out);
+        let mut bridge_vars_in = mem::take(&mut self.bridge_vars_in);
+        for decl in self.own_operands() {
+            decl.push_bridge_var(&mut bridge_vars_out, &mut bridge_vars_in, errors);
+        }
+        self.bridge_vars_out = bridge_vars_out;
+        self.bridge_vars_in = bridge_vars_in;
+        if !self.labels.is_empty() {
+            self.bridge_vars_out.push(BridgeVar {
+                ident: Ident::new(LABEL_VAR, Span::call_site()),
+                part: None,
+                llvm_constraint: (String::from("=r"), self.asm_keyword.span),
+                points_to_mut: false,
+                flag_condition: None,
+                modifier: None,
+                conversion: None,
+                cfg: None
+            });
+        }
+        if self.is_conditional() {
+            self.variants = self.configure(errors);
+        } else {
+            self.finish_operands(errors);
+        }
+        if let Some(ElseBranch::Asm(ref mut block)) = self.else_branch {
+            block.finish(errors);
+        }
+    }
+
+    // Adjusts the operands for the ASM code and translates it.
+    fn finish_operands(&mut self, errors: &mut Vec<parse::Error>) {
+        self.fix_overlapping_clobbers(errors);
+        self.translate(errors);
+        self.add_memory_clobber(errors);
+        self.add_label_stubs();
+    }
+
+    // Makes a variant of the block for each family of targets that it can jump to Rust code on, with a stub at the end
+    // of the ASM code for each label. Other targets get an error instead.
+    fn add_label_stubs(&mut self) {
+        let asm_str = match self.llvm_asm.take() {
+            Some(asm_str) if !self.labels.is_empty() => asm_str,
+            llvm_asm => {
+                self.llvm_asm = llvm_asm;
+                return;
+            }
+        };
+        let operand = self.bridge_vars_out.iter().position(|var| var.ident == LABEL_VAR).unwrap();
+        let intel = self.options.iter().filter_map(AsmOption::llvm_option).any(|option| option.value() == "intel");
+
+        let mut variants = Vec::new();
+        for target in registers::LABEL_TARGETS.iter() {
+            let mut block = self.clone();
+            let code = asm_str.value() + &target.epilogue(operand, self.labels.len(), intel);
+            block.llvm_asm = Some(LitStr::new(&code, asm_str.span()));
+            let target_archs = target.target_archs;
+            let cfg = Condition::Predicate(quote!(any(#(target_arch = #target_archs),*)));
+            variants.push(Variant { cfg: Some(cfg), block, errors: TokenStream::new() });
+        }
+        let target_archs = registers::LABEL_TARGETS.iter().flat_map(|target| target.target_archs.iter());
+        let cfg = Condition::Not(Box::new(Condition::Predicate(quote!(any(#(target_arch = #target_archs),*)))));
+        let errors = quote_spanned!(self.labels[0].label.span()=>
+            compile_error!("an `asm` block can't jump to Rust code on this target");
+        );
+        variants.push(Variant { cfg: Some(cfg), block: self.clone(), errors });
+        self.variants = variants;
+    }
+
+    // Determines whether any of the block's operands or clobbers only exist in some configurations.
+    fn is_conditional(&self) -> bool {
+        self.bridge_vars_out.iter().chain(self.bridge_vars_in.iter()).any(|var| var.cfg.is_some())
+            || self.clobbers.iter().any(|clobber| clobber.cfg.is_some())
+    }
+
+    // Splits the block into a variant for each group of configurations that gives it the same operands and clobbers,
+    // and finishes each one. Configurations where the block itself isn't compiled are skipped.
+    fn configure(&self, errors: &mut Vec<parse::Error>) -> Vec<Variant> {
+        let mut predicates = Vec::new();
+        let var_cfgs = self.bridge_vars_out.iter().chain(self.bridge_vars_in.iter()).map(|var| &var.cfg);
+        let clobber_cfgs = self.clobbers.iter().map(|clobber| &clobber.cfg);
+        for cfg in Some(&self.attrs.cfg).into_iter().chain(var_cfgs).chain(clobber_cfgs).flatten() {
+            cfg.collect_predicates(&mut predicates);
+        }
+        if predicates.len() > cfg::MAX_PREDICATES {
+            errors.push(parse::Error::new(self.asm_keyword.span, format!(
+                "this `asm` block depends on more than {} `#[cfg]` predicates", cfg::MAX_PREDICATES
+            )));
+            return Vec::new();
+        }
+
+        let holds = |cfg: &Option<Condition>, values: &[bool]| match cfg {
+            Some(cfg) => cfg.holds(&predicates, values),
+            None => true
+        };
+        let mut groups = Vec::<(Vec<Condition>, AsmBlock)>::new();
+        for values in cfg::configurations(predicates.len()).filter(|values| holds(&self.attrs.cfg, values)) {
+            let mut block = self.clone();
+            block.else_branch = None;
+            block.fallback = None;
+            while let Some(index) = block.bridge_vars_out.iter().position(|var| !holds(&var.cfg, &values)) {
+                BridgeVarDecl::remove_output(&mut block.bridge_vars_out, &mut block.bridge_vars_in, index);
+            }
+            block.bridge_vars_in.retain(|var| holds(&var.cfg, &values));
+            block.clobbers.retain(|clobber| holds(&clobber.cfg, &values));
+            for var in block.bridge_vars_out.iter_mut().chain(block.bridge_vars_in.iter_mut()) {
+                var.cfg = None;
+            }
+            for clobber in block.clobbers.iter_mut() {
+                clobber.cfg = None;
+            }
+
+            let cfg = Condition::configuration(&predicates, &values);
+            match groups.iter_mut().find(|(_, other)| other.operand_names() == block.operand_names()) {
+                Some((cfgs, _)) => cfgs.push(cfg),
+                None => groups.push((vec![cfg], block))
+            }
+        }
+
+        // If there's only one variant, it's compiled whenever the block is, so its errors can be reported directly.
+        let only = groups.len() == 1;
+        groups.into_iter().map(|(cfgs, mut block)| {
+            let mut variant_errors = Vec::new();
+            block.finish_operands(&mut variant_errors);
+            if only {
+                errors.extend(variant_errors);
+                Variant { cfg: None, block, errors: TokenStream::new() }
+            } else {
+                let variant_errors = variant_errors.iter().map(parse::Error::to_compile_error).collect();
+                Variant { cfg: Some(Condition::Any(cfgs)), block, errors: variant_errors }
+            }
+        }).collect()
+    }
+
+    // The names and constraints of the block's outputs, inputs, and clobbers
+    fn operand_names(&self) -> [Vec<(String, &str)>; 3] {
+        fn names(vars: &[BridgeVar]) -> Vec<(String, &str)> {
+            vars.iter().map(|var| (var.name(), var.constraint_as_str())).collect()
+        }
+        let clobbers = self.clobbers.iter().map(|clobber| (String::new(), clobber.constraint_as_str())).collect();
+        [names(&self.bridge_vars_out), names(&self.bridge_vars_in), clobbers]
+    }
+
+    // Returns the operands that the ASM code gives as Rust expressions, in every variant of the block.
+    fn all_inline_operands(&self) -> Vec<&InlineOperand> {
+        if self.variants.is_empty() {
+            self.inline_operands.iter().collect()
+        } else {
+            self.variants.iter().flat_map(|variant| variant.block.inline_operands.iter()).collect()
+        }
+    }
+
+    fn target_arch(&self) -> Option<&LitStr> {
+        self.options.iter().filter_map(|option| match option {
+            AsmOption::TargetArch(target_arch) => Some(target_arch),
+            _ => None
+        }).next()
+    }
+
+    // Returns this block and each of its `asm` alternatives, in order.
+    fn chain(&self) -> Vec<&AsmBlock> {
+        let mut chain = vec![self];
+        while let Some(ElseBranch::Asm(ref block)) = chain[chain.len() - 1].else_branch {
+            chain.push(block);
+        }
+        chain
+    }
+
+    // Returns the `else` block at the end of the chain, if there is one.
+    fn rust_alternative(&self) -> Option<(&Brace, &TokenStream)> {
+        match self.chain().last()?.else_branch {
+            Some(ElseBranch::Rust(ref brace, ref tts)) => Some((brace, tts)),
+            _ => None
+        }
+    }
+
+    // Returns the `fallback` block of the chain, if there is one. It's stored with the last `asm` block.
+    fn fallback(&self) -> Option<(&Brace, &TokenStream)> {
+        self.chain().last()?.fallback.as_ref().map(|(_, brace, tts)| (brace, tts))
+    }
+
+    // Checks that the alternatives of a chain can be told apart and that the Rust code does what the ASM code does.
+    fn check_alternatives(&self, errors: &mut Vec<parse::Error>) {
+        let chain = self.chain();
+
+        let mut target_archs = Vec::<&LitStr>::new();
+        for alternative in chain.iter().filter(|_| self.else_branch.is_some()) {
+            match alternative.target_arch() {
+                Some(target_arch) => {
+                    if target_archs.iter().any(|other| other.value() == target_arch.value()) {
+                        errors.push(parse::Error::new(target_arch.span(),
+                            format!("more than one alternative is for `target_arch = \"{}\"`", target_arch.value())));
+                    }
+                    target_archs.push(target_arch);
+                },
+                None => errors.push(error_with_help(alternative.asm_keyword.span,
+                    "an `asm` block with an `else` branch needs a target",
+                    "add an option like `target_arch = \"x86_64\"`"))
+            }
+        }
+
+        // The whole chain is an expression if any of it is, and every alternative has to evaluate to the same shape.
+        for alternative in chain.iter().skip(1) {
+            match (&self.yields, &alternative.yields) {
+                (Some(first), Some(yields)) if first.shape() != yields.shape() => {
+                    errors.push(parse::Error::new(alternative.asm_keyword.span, format!(
+                        "this alternative yields {}, but the first one yields {}", yields.describe(), first.describe()
+                    )));
+                },
+                (Some(_), None) | (None, Some(_)) => errors.push(parse::Error::new(alternative.asm_keyword.span,
+                    "either every alternative in a chain yields outputs or none of them do")),
+                _ => {}
+            }
+        }
+
+        // Each label needs a name of its own.
+        for alternative in chain.iter() {
+            for (i, arm) in alternative.labels.iter().enumerate() {
+                let name = arm.label.to_string();
+                if alternative.labels[.. i].iter().any(|other| other.label == arm.label) {
+                    errors.push(parse::Error::new(arm.label.span(), format!("label `{}` is defined more than once", name)));
+                } else if alternative.bridge_vars_out.iter().chain(alternative.bridge_vars_in.iter())
+                        .any(|var| var.ident == name) {
+                    errors.push(parse::Error::new(arm.label.span(),
+                        format!("label `{}` has the same name as a bridge variable", name)));
+                }
+            }
+            if let (true, Some(arm)) = (alternative.is_noreturn(), alternative.labels.first()) {
+                errors.push(parse::Error::new(arm.label.span(), "a `noreturn` block can't jump to Rust code"));
+            }
+        }
+
+        // A block that never finishes can't have outputs, since nothing would ever read them.
+        for alternative in chain.iter().filter(|alternative| alternative.is_noreturn()) {
+            if alternative.yields.is_some() {
+                errors.push(parse::Error::new(alternative.asm_keyword.span, "a `noreturn` block can't yield outputs"));
+            }
+            for decl in alternative.header_operands() {
+                if let ConstraintKeyword::Out | ConstraintKeyword::InOut = decl.constraint_keyword {
+                    errors.push(parse::Error::new(decl.ident.span(), "a `noreturn` block can't have outputs"));
+                }
+            }
+            // Declared outputs and labels are reported above.
+            let mut outputs = Vec::<&Ident>::new();
+            for var in alternative.output_vars() {
+                let name = var.ident.to_string();
+                if !outputs.contains(&&var.ident) && !alternative.declares_var(&name) && name != LABEL_VAR {
+                    outputs.push(&var.ident);
+                }
+            }
+            if !outputs.is_empty() {
+                let outputs = outputs.iter().map(|ident| format!("`{}`", ident)).collect::<Vec<_>>().join(", ");
+                errors.push(error_with_help(alternative.asm_keyword.span,
+                    format!("a `noreturn` block can't have outputs, but this one has {}", outputs),
+                    "every output bridge variable in scope is an output of the block, even if it isn't used"
+                ));
+            }
+        }
+
+        // Every output that the ASM code sets has to be set by the Rust code too.
+        let rust_blocks = self.rust_alternative().map(|block| ("else", block)).into_iter()
+            .chain(self.fallback().map(|block| ("fallback", block)));
+        for (keyword, (brace, tts)) in rust_blocks {
+            let mut missing = Vec::<&Ident>::new();
+            for var in self.bridge_vars_out.iter() {
+                if chain.iter().any(|alternative| alternative.declares_var(&var.ident.to_string())) {
+                    continue; // The Rust code evaluates to these instead.
+                }
+                if !missing.contains(&&var.ident) && !assigns(tts.clone(), &var.ident)
+                        && chain.iter().any(|alternative| alternative.uses_var(&var.ident.to_string())) {
+                    missing.push(&var.ident);
+                }
+            }
+            if !missing.is_empty() {
+                let missing = missing.iter().map(|ident| format!("`{}`", ident)).collect::<Vec<_>>().join(", ");
+                errors.push(parse::Error::new(brace.span,
+                    format!("the `{}` block has to assign the same outputs as the `asm` blocks, but it doesn't \
+                        assign {}", keyword, missing)));
+            }
+        }
+    }
+
+    // Returns the operands that are declared in this block's header or yielded by it, in that order. They're only in
+    // scope in this block.
+    fn own_operands(&self) -> impl Iterator<Item = &BridgeVarDecl> {
+        self.header_operands().chain(self.yields.iter().flat_map(|yields| yields.decls.iter()))
+    }
+
+    fn header_operands(&self) -> impl Iterator<Item = &BridgeVarDecl> {
+        self.options.iter().filter_map(|option| match option {
+            AsmOption::Operand(decl) => Some(&**decl),
+            _ => None
+        })
+    }
+
+    // Determines whether the named variable is one of this block's own operands, which hides any bridge variable with
+    // the same name.
+    fn declares_var(&self, name: &str) -> bool {
+        self.own_operands().any(|decl| decl.ident == name)
+    }
+
+    // Reports operands in the header of this block or its alternatives that the ASM code doesn't use.
+    fn check_operand_usage(&self, errors: &mut Vec<parse::Error>) {
+        for alternative in self.chain() {
+            for decl in alternative.header_operands() {
+                let name = decl.ident.to_string();
+                if !name.starts_with('_') && !alternative.uses_var(&name) {
+                    alternative.lints.emit(Lint::UnusedBridgeVar, decl.ident.span(), "bridge variable not used",
+                        decl.constraint_string.span(), "it isn't referenced in the `asm` block", errors);
+                }
+            }
+        }
+    }
+
+    fn is_checked(&self) -> bool {
+        self.options.iter().any(|option| matches!(option, AsmOption::Checked))
+    }
+
+    fn is_noreturn(&self) -> bool {
+        self.options.iter().any(|option| matches!(option, AsmOption::NoReturn))
+    }
+
+    // Returns an `asm!` invocation for each target that supports the `checked` option, surrounding the ASM code with
+    // snapshots of the registers, followed by a check that every register that changed was declared.
+    fn checked_invocations(&self, asm_str: &LitStr, constraints_out: &[TokenStream], constraints_in: &[TokenStream],
+            constraints_clobber: &[LitStr], options: &[&LitStr], chosen_outputs: &[usize]) -> TokenStream {
+        let intel = options.iter().any(|option| option.value() == "intel");
+        // The compiler has to know that the snapshots are written.
+        let memory = if constraints_clobber.iter().any(|clobber| clobber.value() == "memory") {
+            None
+        } else {
+            Some(LitStr::new("memory", Span::call_site()))
+        };
+
+        let mut tokens = TokenStream::new();
+        for target in registers::CHECKED_TARGETS.iter() {
+            let target_arch = target.target_arch;
+            // The buffer's address is the last operand, after the inputs.
+            let address_operand = constraints_out.len() + constraints_in.len();
+            let code = format!("{}\n\t{}\n\t{}", target.snapshot(false, &[], address_operand, intel), asm_str.value(),
+                target.snapshot(true, chosen_outputs, address_operand, intel));
+            let code = LitStr::new(&code, asm_str.span());
+            let count = target.registers.len();
+            let snapshots_len = 3 * count;
+            // Registers that the block declares can change. The stack pointer can't be declared, so it's always checked.
+            let checked_registers = target.registers.iter().enumerate()
+                .filter(|&(_, register)| !self.declares_register(register))
+                .map(|(i, register)| quote!((#i, #register)));
+            let clobbers = constraints_clobber.iter().chain(memory.iter());
+            tokens.append_all(quote!(
+                #[cfg(all(debug_assertions, target_arch = #target_arch))] {
+                    let mut __rusty_asm_snapshots = [0usize; #snapshots_len];
+                    let __rusty_asm_address = __rusty_asm_snapshots.as_mut_ptr();
+                    asm!(#code : #(#constraints_out),* : #(#constraints_in,)* "m"(__rusty_asm_address) :
+                        #(#clobbers),* : #(#options),*);
+                    for &(i, register) in [#(#checked_registers),*].iter() {
+                        if __rusty_asm_snapshots[i] != __rusty_asm_snapshots[#count + i]
+                                && __rusty_asm_snapshots[2 * #count + i] == 0 {
+                            panic!("the ASM code changed `{}`, which isn't declared as an output or a clobber", register);
+                        }
+                    }
+                }
+            ));
+        }
+        tokens
+    }
+
+    // Determines whether the block declares that it changes any part of the named register, as an output or a clobber.
+    fn declares_register(&self, register: &str) -> bool {
+        self.clobbers.iter().any(|clobber| registers::overlap(clobber.constraint_as_str(), register))
+            || self.bridge_vars_out.iter().filter_map(BridgeVar::explicit_register)
+                .any(|output| registers::overlap(output, register))
+    }
+
+    // Translates the ASM code into LLVM's format.
+    fn translate(&mut self, errors: &mut Vec<parse::Error>) {
+        if let Some(ref asm_unchanged) = self.asm_unchanged {
+            // Replace every occurrence of `$<ident>` in the ASM code with the appropriate `$0`, `$1`, etc.
+            let (llvm_asm, used_idents, inline_operands) = self.replace_identifiers(asm_unchanged, errors);
+            self.llvm_asm = Some(LitStr::new(llvm_asm.as_str(), asm_unchanged.span()));
+            self.used_idents = used_idents;
+            self.bridge_vars_in.extend(inline_operands.iter().map(|operand| operand.var.clone()));
+            self.inline_operands = inline_operands;
+        }
+    }
+
+    // Makes sure the compiler knows that memory can change if the ASM code has a pointer to mutable memory, unless the
+    // `nomem` or `readonly` option promises that it doesn't write to memory.
+    fn add_memory_clobber(&mut self, errors: &mut Vec<parse::Error>) {
+        let memory = Clobber { llvm_constraint: (String::from("memory"), Span::call_site()), cfg: None };
+        let memory_option = self.options.iter().filter_map(|option| match option {
+            AsmOption::Memory(ident) => Some(ident),
+            _ => None
+        }).next();
+        if let Some(ident) = memory_option {
+            if ident == "nomem" && self.clobbers.contains(&memory) {
+                errors.push(parse::Error::new(ident.span(), "`nomem` can't be used along with `clobber(\"memory\")`"));
+            }
+            return;
+        }
+        if self.clobbers.contains(&memory) {
+            return;
+        }
+
+        let var = self.bridge_vars_in.iter().find(|var| var.points_to_mut && self.uses_var(&var.ident.to_string()));
+        if let Some(var) = var {
+            let asm_span = self.asm_unchanged.as_ref().map_or(var.constraint_span(), LitStr::span);
+            self.lints.emit(Lint::ImplicitMemoryClobber, asm_span,
+                format!("added `clobber(\"memory\")`, since `{}` may point to mutable memory", var.ident),
+                var.ident.span(),
+                "add `clobber(\"memory\")` to make this explicit, or add the `readonly` or `nomem` option if the ASM \
+                code doesn't write through it",
+                errors);
+            self.clobbers.push(Clobber { llvm_constraint: (String::from("memory"), var.constraint_span()), cfg: None });
+        }
+    }
+
+    // Determines whether this block uses the named bridge variable, either by referencing it in the ASM code or by
+    // binding it to an explicit register or a flag. A block with no ASM code doesn't use anything.
+    fn uses_var(&self, name: &str) -> bool {
+        if !self.variants.is_empty() {
+            return self.variants.iter().any(|variant| variant.block.uses_var(name));
+        }
+        self.llvm_asm.is_some() && self.bridge_vars_out.iter().chain(self.bridge_vars_in.iter())
+            .any(|var| var.ident == name && (self.used_idents.contains_key(&var.name()) ||
+                var.explicit_register().is_some() || var.flag_condition.is_some()))
+    }
+
+    // Returns the outputs of every `asm!` invocation that the block expands to, including any that it doesn't use.
+    fn output_vars(&self) -> Vec<&BridgeVar> {
+        if !self.variants.is_empty() {
+            return self.variants.iter().flat_map(|variant| variant.block.output_vars()).collect();
+        }
+        match self.llvm_asm {
+            Some(_) => self.bridge_vars_out.iter().collect(),
+            None => Vec::new()
+        }
+    }
+
+    // Every output is bound to every `asm!` invocation in its scope, so the block writes to it whether it's referenced
+    // or not.
+    fn writes_var(&self, name: &str) -> bool {
+        if !self.variants.is_empty() {
+            return self.variants.iter().any(|variant| variant.block.writes_var(name));
+        }
+        self.llvm_asm.is_some() && self.bridge_vars_out.iter().any(|var| var.ident == name)
+    }
+
+    // Likewise, the block reads every input in its scope.
+    fn reads_var(&self, name: &str) -> bool {
+        if !self.variants.is_empty() {
+            return self.variants.iter().any(|variant| variant.block.reads_var(name));
+        }
+        self.llvm_asm.is_some() && self.bridge_vars_in.iter().any(|var| var.ident == name)
+    }
+
+    // Replaces every occurrence of `$<ident>` in the ASM code with the appropriate numeral reference to an
+    // input or output register, if the identifier matches a bridge variable. Also returns the span of the first
+    // reference to each bridge variable that was used, and the operands that are given as Rust expressions, which
+    // need to be added after the existing inputs.
+    fn replace_identifiers(&self, asm: &LitStr, errors: &mut Vec<parse::Error>)
+            -> (String, HashMap<String, Span>, Vec<InlineOperand>) {
+        let orig = asm.value();
+        let mut result = String::new();
+        let mut used_idents = HashMap::new();
+        let mut inline_operands = Vec::<InlineOperand>::new();
         let mut chars = orig.chars();
         while let Some(c) = chars.next() {
             result.push(c);
             if c == '$' {
                 let rest = chars.as_str();
+                let start = orig.len() - rest.len() - 1; // The byte offset of the `$`
                 if let Some(c2) = chars.next() {
                     if c2 == '$' {
                         // Keep the "$$" around so LLVM will see it.
                         result.push(c2);
-                    } else if let Some((ident, replacement)) = self.consume_translate_ident(rest, &mut chars, span) {
+                    } else if c2 == '{' && !rest[1 ..].starts_with(|c: char| c.is_ascii_digit() || c == ':') {
+                        // A Rust expression, like `${a + 1}`. (Something like `${0:q}` or `${:uid}` is left for LLVM.)
+                        match self.consume_inline_operand(rest, &mut chars, asm, start, inline_operands.len(), errors) {
+                            Some((operand, replacement)) => {
+                                result.push_str(replacement.as_str());
+                                used_idents.insert(operand.var.name(), operand.var.constraint_span());
+                                inline_operands.push(operand);
+                            },
+                            None => result.push(c2)
+                        }
+                    } else if let Some((ident, replacement, span)) =
+                            self.consume_translate_ident(rest, &mut chars, asm, start, errors) {
                         // A defined identifier was found. Replace it with its position in the register lists.
                         result.push_str(replacement.as_str());
-                        used_idents.insert(ident);
+                        used_idents.entry(ident).or_insert(span);
                     } else {
-                        // No identifier found. Issue a warning.
+                        // No identifier found. (A warning has already been issued.)
                         result.push(c2);
-                        warn(span, "expected an identifier after `$`");
-                        help(span, "you can include a literal dollar sign by using `$$`");
                     }
                 } else {
                     // No more characters. Issue a warning.
-                    warn(span, "unexpected end of asm block after `$`");
-                    help(span, "you can include a literal dollar sign by using `$$`");
+                    self.lint_in_code(Lint::StrayDollar, asm, start .. start + 1, "unexpected end of asm block after `$`",
+                        "you can include a literal dollar sign by using `$$`", errors);
                 }
             }
         }
-        (result, used_idents)
+        (result, used_idents, inline_operands)
     }
 
-    // Consumes and translates the next identifier if there is an identifier here.
-    // When this is called, `chars` should be one character ahead of `orig`.
-    fn consume_translate_ident(&self, orig: &str, chars: &mut Chars, span: Span) -> Option<(String, String)> {
+    // Consumes an operand that's given as a Rust expression, like `${a + 1}` or `${a + 1:reg}`, and returns it along
+    // with its replacement. `index` is the number of such operands that came before it in the ASM code.
+    // When this is called, `chars` should be one character ahead of `orig`, which starts with the `{`, and `start`
+    // should be the byte offset of the `$` in the whole ASM code.
+    fn consume_inline_operand(&self, orig: &str, chars: &mut Chars, asm: &LitStr, start: usize, index: usize,
+            errors: &mut Vec<parse::Error>) -> Option<(InlineOperand, String)> {
+        let mut depth = 0;
+        let end = orig.char_indices().find(|&(_, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        }).map(|(i, _)| i);
+        let end = match end {
+            Some(end) => end,
+            None => {
+                let (span, note) = diagnostics::locate(asm, start .. start + 2);
+                errors.push(parse::Error::new(span, format!("unclosed `${{`{}", note)));
+                return None;
+            }
+        };
+        let content = &orig[1 .. end];
+        chars.nth(content.chars().count()); // Skip past the closing brace.
+        let (span, note) = diagnostics::locate(asm, start .. start + end + 2);
+
+        // The register class comes after the last colon, unless it's part of a path.
+        let (source, class_name) = match content.rfind(':') {
+            Some(colon) if !content[.. colon].ends_with(':') && Self::parse_ident_at_start(content[colon + 1 ..].trim())
+                    .map_or(false, |(name, _)| name == content[colon + 1 ..].trim()) =>
+                (&content[.. colon], content[colon + 1 ..].trim()),
+            _ => (content, "reg")
+        };
+        let class = match RegisterClass::lookup(class_name) {
+            Some(class) => class,
+            None => {
+                errors.push(parse::Error::new(span, format!("unknown register class `{}`{}", class_name, note)));
+                return None;
+            }
+        };
+        let expr = match source.parse::<TokenStream>().map_err(|_| ()).and_then(|tokens| {
+            syn::parse2::<Expr>(respan(tokens, span)).map_err(|_| ())
+        }) {
+            Ok(expr) => expr,
+            Err(()) => {
+                errors.push(parse::Error::new(span, format!("expected a Rust expression in `${{...}}`{}", note)));
+                return None;
+            }
+        };
+
+        let var = BridgeVar {
+            ident: Ident::new(&format!("__rusty_asm_expr_{}", index), Span::call_site()),
+            part: None,
+            llvm_constraint: (String::from(class.llvm_constraint), span),
+            points_to_mut: is_mutable_pointer_expr(&expr),
+            flag_condition: None,
+            modifier: class.modifier,
+            conversion: None,
+            cfg: None
+        };
+        let replacement = var.reference(self.bridge_vars_out.len() + self.bridge_vars_in.len() + index);
+        Some((InlineOperand { var, expr, source: source.trim().to_string(), class }, replacement))
+    }
+
+    // Consumes and translates the next identifier if there is an identifier here. Returns the identifier, its
+    // replacement, and the span of the reference.
+    // When this is called, `chars` should be one character ahead of `orig`, and `start` should be the byte offset of
+    // the `$` in the whole ASM code.
+    fn consume_translate_ident(&self, orig: &str, chars: &mut Chars, asm: &LitStr, start: usize,
+            errors: &mut Vec<parse::Error>) -> Option<(String, String, Span)> {
         let output_regs_count = self.bridge_vars_out.len();
-        if let Some((ident, length)) = Self::parse_ident_at_start(orig) {
-            // There's a valid identifier here. Let's see if it corresponds to a bridge variable.
-            if let Some(index) = Self::find_var_by_ident(&self.bridge_vars_out, &ident) {
+        if let Some((mut ident, mut length)) = Self::parse_ident_at_start(orig) {
+            // The parts of a slice or a wide variable are referenced like `$<ident>.ptr` or `$<ident>.lo`.
+            if let Some(part_name) = Part::parse_suffix(&orig[ident.len() ..]) {
+                let name = format!("{}.{}", ident, part_name);
+                if Self::find_var_by_ident(&self.bridge_vars_out, &name).is_some() ||
+                        Self::find_var_by_ident(&self.bridge_vars_in, &name).is_some() {
+                    ident = name;
+                    length += 1 + part_name.len();
+                }
+            }
+            let range = start .. start + 1 + ident.len();
+            // There's a valid identifier here. Let's see if it corresponds to a label or a bridge variable.
+            if let Some(index) = self.labels.iter().position(|arm| arm.label == ident) {
+                if length > 1 {
+                    chars.nth(length - 2); // Skip past the identifier.
+                }
+                // The `$` is already in the result.
+                Some((ident, registers::label_name(index)[1 ..].to_string(), diagnostics::locate(asm, range).0))
+            } else if let Some(index) = Self::find_var_by_ident(&self.bridge_vars_out, &ident) {
                 // Found the identifier in the `out` bridge vars.
                 if length > 1 {
                     chars.nth(length - 2); // Skip past the identifier.
                 }
-                Some((ident, format!("{}", index)))
+                let (span, note) = diagnostics::locate(asm, range);
+                if self.bridge_vars_out[index].flag_condition.is_some() {
+                    // There's no register to substitute.
+                    errors.push(error_with_help(span,
+                        format!("flag output `{}` can't be referenced in ASM code{}", ident, note),
+                        "it's set from the flags after the ASM code runs"
+                    ));
+                    return None;
+                }
+                Some((ident, self.bridge_vars_out[index].reference(index), span))
             } else if let Some(index) = Self::find_var_by_ident(&self.bridge_vars_in, &ident) {
                 // Found the identifier in the `in` bridge variables.
                 if length > 1 {
                     chars.nth(length - 2); // Skip past the identifier.
                 }
-                Some((ident, format!("{}", index + output_regs_count)))
+                Some((ident, self.bridge_vars_in[index].reference(index + output_regs_count),
+                    diagnostics::locate(asm, range).0))
+            } else if let Some(var) = self.bridge_vars_out.iter().chain(self.bridge_vars_in.iter())
+                    .find(|var| var.part.is_some() && var.ident == ident) {
+                // It's a slice or a wide variable, but it has to be split into its parts.
+                let part_names = match var.part {
+                    Some(Part::Ptr) | Some(Part::Len) => "`.ptr` or `.len`",
+                    _ => "`.lo` or `.hi`"
+                };
+                self.lint_in_code(Lint::UnrecognizedBridgeVar, asm, range,
+                    format!("bridge variable `{}` used without {}", ident, part_names),
+                    "the variable is split across two registers, so each part has to be referenced separately", errors);
+                None
             } else {
                 // Couldn't find the identifier anywhere. Issue a warning.
-                warn(span, format!("unrecognized bridge variable `{}`", ident));
-                help(span, "it must be declared in this `rusty_asm` block with `in`, `out`, or `inout`");
+                self.lint_in_code(Lint::UnrecognizedBridgeVar, asm, range, format!("unrecognized bridge variable `{}`", ident),
+                    "it must be declared in this `rusty_asm` block with `in`, `out`, or `inout`", errors);
                 None
             }
         } else {
             // Not a valid identifier. Issue a warning.
-            warn(span, "expected an identifier after `$`");
-            help(span, "you can include a literal dollar sign by using `$$`");
+            let end = start + 1 + orig.chars().next().map_or(0, char::len_utf8);
+            self.lint_in_code(Lint::StrayDollar, asm, start .. end, "expected an identifier after `$`",
+                "you can include a literal dollar sign by using `$$`", errors);
             None
         }
     }
 
+    // Reports a lint about the given byte range of the ASM code, pointing to it as precisely as possible.
+    fn lint_in_code<T: Display>(&self, lint: Lint, asm: &LitStr, range: Range<usize>, message: T, help_message: &str,
+            errors: &mut Vec<parse::Error>) {
+        let (span, note) = diagnostics::locate(asm, range);
+        self.lints.emit(lint, span, format!("{}{}", message, note), asm.span(), help_message, errors);
+    }
+
     fn parse_ident_at_start(text: &str) -> Option<(String, usize)> {
         let mut chars = text.chars();
         let mut result = String::new();
@@ -561,9 +2440,9 @@ impl AsmBlock {
         }
     }
 
-    fn find_var_by_ident(vars: &Vec<BridgeVar>, ident_string: &String) -> Option<usize> {
+    fn find_var_by_ident(vars: &[BridgeVar], ident_string: &String) -> Option<usize> {
         for (i, var) in vars.iter().enumerate() {
-            if format!("{}", var.ident) == *ident_string {
+            if var.name() == *ident_string {
                 return Some(i);
             }
         }
@@ -571,94 +2450,404 @@ impl AsmBlock {
     }
 
     // Makes sure that the list of clobbers has nothing in common with the lists of inputs and outputs. The `asm!` macro
-    // may or may not require that, and it doesn't hurt in any case.
-    fn fix_overlapping_clobbers(&mut self) {
+    // may or may not require that, and it doesn't hurt in any case. Registers overlap if any part of them is shared, so
+    // `clobber("ax")` overlaps `out("{eax}")`.
+    fn fix_overlapping_clobbers(&mut self, errors: &mut Vec<parse::Error>) {
         // If a clobber is the same as an output, remove the clobber and produce a warning, since
         // that may or may not be what the programmer expects. In any case, having both an `out`
-        // variable and a clobber is confusing to the reader, so one should be removed.
+        // variable and a clobber is confusing to the reader, so one should be removed. That only
+        // works if the output covers the whole clobber, though.
         for var in self.bridge_vars_out.iter() {
             if let Some(reg) = var.explicit_register() {
-                for clobber in self.clobbers.clone().iter() {
-                    if clobber.constraint_as_str() == reg {
-                        warn(clobber.span(), "clobber points to same register as an output; ignoring clobber");
-                        help(var.constraint_span(), "output declared here");
-                        self.clobbers.remove(&clobber);
-                        break; // There are already no duplicate clobbers.
+                let mut i = 0;
+                while i < self.clobbers.len() {
+                    let clobber = self.clobbers[i].constraint_as_str();
+                    if !registers::overlap(reg, clobber) {
+                        i += 1;
+                    } else if registers::covers(reg, clobber) {
+                        let clobber = self.clobbers.remove(i);
+                        self.lints.emit(Lint::ClobberOverlap, clobber.span(),
+                            "clobber points to same register as an output; ignoring clobber",
+                            var.constraint_span(), "output declared here", errors);
+                    } else {
+                        errors.push(Self::partial_overlap_error(&self.clobbers[i], "output", var, reg));
+                        i += 1;
                     }
                 }
             }
         }
 
         // If a clobber is the same as an input, change the clobber into an output, bound to the
-        // same variable (since the `asm!` macro won't let us bind something to `_`).
-        for (i, var) in self.bridge_vars_in.clone().iter().enumerate() {
+        // same variable (since the `asm!` macro won't let us bind something to `_`). The input
+        // keeps its position and is tied to the new output, which is added after all the others.
+        for i in 0 .. self.bridge_vars_in.len() {
+            let var = self.bridge_vars_in[i].clone();
             if let Some(reg) = var.explicit_register() {
-                for clobber in self.clobbers.clone().iter() {
-                    if clobber.constraint_as_str() == reg {
-                        // Add the output and link the input to it.
-                        let out_constraint = format!("={}", var.constraint_as_str());
-                        let in_constraint = format!("{}", self.bridge_vars_out.len());
-                        self.bridge_vars_out.push(BridgeVar {
-                            ident: var.ident.clone(),
-                            llvm_constraint: (out_constraint, var.constraint_span())
-                        });
-                        self.bridge_vars_in.remove(i);
-                        self.bridge_vars_in.push(BridgeVar {
-                            ident: var.ident.clone(),
-                            llvm_constraint: (in_constraint, var.constraint_span())
-                        });
-                        // Remove the clobber.
-                        self.clobbers.remove(&clobber);
-                        break;
+                let mut clobbered = false;
+                let mut j = 0;
+                while j < self.clobbers.len() {
+                    let clobber = self.clobbers[j].constraint_as_str();
+                    if !registers::overlap(reg, clobber) {
+                        j += 1;
+                    } else if registers::covers(reg, clobber) {
+                        self.clobbers.remove(j);
+                        clobbered = true;
+                    } else {
+                        errors.push(Self::partial_overlap_error(&self.clobbers[j], "input", &var, reg));
+                        j += 1;
                     }
                 }
+
+                if clobbered && var.part.is_some() {
+                    // There's no variable to bind the output to.
+                    errors.push(parse::Error::new(var.constraint_span(), format!(
+                        "the register of `{}` is clobbered, but only a whole bridge variable can be turned into an \
+                        output; bind `{}` to another register", var.name(), var.name()
+                    )));
+                } else if clobbered {
+                    // Add the output and link the input to it.
+                    let out_constraint = format!("={}", var.constraint_as_str());
+                    let in_constraint = format!("{}", self.bridge_vars_out.len());
+                    self.bridge_vars_out.push(BridgeVar {
+                        ident: var.ident.clone(),
+                        part: None,
+                        llvm_constraint: (out_constraint, var.constraint_span()),
+                        points_to_mut: false,
+                        flag_condition: None,
+                        modifier: var.modifier,
+                        conversion: var.conversion.clone(),
+                        cfg: var.cfg.clone()
+                    });
+                    self.bridge_vars_in[i].llvm_constraint = (in_constraint, var.constraint_span());
+                }
+            }
+        }
+    }
+
+    // A clobber that covers more of a register than a bridge variable does can't be fixed automatically, since the rest
+    // of the register would no longer be marked as clobbered.
+    fn partial_overlap_error(clobber: &Clobber, kind: &str, var: &BridgeVar, reg: &str) -> parse::Error {
+        parse::Error::new(clobber.span(), format!(
+            "clobber `{}` overlaps {} `{}` (`{}`), but the {} doesn't cover the whole clobbered register; bind `{}` to \
+            `{{{}}}` instead",
+            clobber.constraint_as_str(), kind, var.ident, reg, kind, var.ident, clobber.constraint_as_str()
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum AsmOption {
+    // An option for `asm!`, like `"volatile"`
+    Llvm(LitStr),
+    // `allow(<lints>)`, `warn(<lints>)`, or `deny(<lints>)`, which sets the levels of our lints for one `asm` block
+    Lint(Level, Punctuated<Ident, Token![,]>),
+    // `nomem` or `readonly`, which promises that the ASM code doesn't write to memory
+    Memory(Ident),
+    // `target_arch = "<arch>"`, which limits the block to one target so that it can be chained with alternatives
+    TargetArch(LitStr),
+    // `checked`, which makes sure in debug builds that the ASM code only changes the registers it declares
+    Checked,
+    // `trace` or `trace = <hook>`, which logs the values of the bridge variables in debug builds
+    Trace(Ident, Option<Path>),
+    // `noreturn`, which promises that the ASM code never finishes, so the block diverges
+    NoReturn,
+    // `<constraint> <ident> [= <expr>]`, which declares an operand for this block alone, as in `in("r") x = a + b`
+    Operand(Box<BridgeVarDecl>)
+}
+
+impl Parse for AsmOption {
+    fn parse(input: ParseStream) -> parse::Result<Self> {
+        if input.peek(LitStr) {
+            return Ok(AsmOption::Llvm(input.parse()?));
+        }
+        if input.peek(Token![in]) || input.peek(keyword::out) || input.peek(keyword::inout)
+                || input.peek(keyword::slice_in) {
+            let mut decl = BridgeVarDecl::parse_inline(input, None)?;
+            decl.parse_assignment(input)?;
+            // An `inout` operand is written by the ASM code, so it doesn't need to be declared `mut`.
+            if let ConstraintKeyword::InOut = decl.constraint_keyword {
+                decl.mut_keyword = decl.mut_keyword.or_else(|| Some(Token![mut](decl.ident.span())));
             }
+            if decl.assignment.is_none() && !matches!(decl.constraint_keyword, ConstraintKeyword::Out) {
+                return Err(error_with_help(decl.ident.span(),
+                    "an input that's declared in an `asm` block's header needs a value",
+                    &format!("add `= <expr>` after `{}`", decl.ident)));
+            }
+            return Ok(AsmOption::Operand(Box::new(decl)));
+        }
+        let ident = input.parse::<Ident>()?;
+        if ident == "nomem" || ident == "readonly" {
+            return Ok(AsmOption::Memory(ident));
+        }
+        if ident == "checked" {
+            return Ok(AsmOption::Checked);
+        }
+        if ident == "noreturn" {
+            return Ok(AsmOption::NoReturn);
+        }
+        if ident == "trace" {
+            if input.peek(Token![=]) {
+                input.parse::<Token![=]>()?;
+                return Ok(AsmOption::Trace(ident, Some(input.parse()?)));
+            }
+            return Ok(AsmOption::Trace(ident, None));
+        }
+        if ident == "target_arch" {
+            input.parse::<Token![=]>()?;
+            return Ok(AsmOption::TargetArch(input.parse()?));
+        }
+        match Level::from_name(&ident.to_string()) {
+            Some(level) => {
+                let content = parenthesized(input)?;
+                Ok(AsmOption::Lint(level, content.call(Punctuated::parse_terminated)?))
+            },
+            None => Err(parse::Error::new(ident.span(),
+                "expected a string literal, an operand, `nomem`, `readonly`, `target_arch`, `checked`, `trace`, \
+                `noreturn`, `allow`, `warn`, or `deny`"))
+        }
+    }
+}
+
+impl AsmOption {
+    fn llvm_option(&self) -> Option<&LitStr> {
+        match *self {
+            AsmOption::Llvm(ref option) => Some(option),
+            AsmOption::Lint(..) | AsmOption::Memory(_) | AsmOption::TargetArch(_) | AsmOption::Checked
+                | AsmOption::Trace(..) | AsmOption::NoReturn | AsmOption::Operand(_) => None
         }
     }
 }
 
+// An operand that the ASM code gives as a Rust expression, like `${a + 1}` or `${a + 1:reg}`
+#[derive(Debug, Clone)]
+struct InlineOperand {
+    // An input bound to a temporary variable that holds the expression's value
+    var: BridgeVar,
+    expr: Expr,
+    // The expression as it's written in the ASM code
+    source: String,
+    class: &'static RegisterClass
+}
+
 #[derive(Debug, Clone)]
 struct BridgeVar {
     ident: Ident,
-    llvm_constraint: (String, Span)
+    // Which part of the variable this operand is bound to, if the variable is a slice or a wide variable
+    part: Option<Part>,
+    llvm_constraint: (String, Span),
+    // Whether this is an input that the ASM code might use to write to memory
+    points_to_mut: bool,
+    // The condition code that a flag output tests, like `z`
+    flag_condition: Option<String>,
+    // The modifier that references in the ASM code need, like `q` in `${0:q}`
+    modifier: Option<&'static str>,
+    // The variable's Rust type and the type it has in the register, if they're different
+    conversion: Option<(Type, Type)>,
+    // The configurations that the operand exists in, if it doesn't exist in all of them
+    cfg: Option<Condition>
 }
 
 impl BridgeVar {
-    fn constraint_as_tokens(&self) -> TokenStream {
+    // Returns the operand as it should appear in an `asm!` invocation. If `use_span` is given, it replaces the span of
+    // the variable's identifier.
+    fn constraint_as_tokens(&self, use_span: Option<Span>) -> TokenStream {
         let constraint = LitStr::new(self.llvm_constraint.0.as_str(), self.llvm_constraint.1);
-        let ident = &self.ident;
-        quote!(#constraint(#ident))
+        let mut ident = self.ident.clone();
+        if let Some(span) = use_span {
+            ident.set_span(span);
+        }
+        if let Some((ref rust_type, ref register_type)) = self.conversion {
+            // A converted output goes to a temporary variable, like a wide output. A register type of `_` means the
+            // type's `AsmOperand` implementation does the conversion. A `bool` is cast, so that it can go in a register
+            // of any width, just like a `bool` output.
+            return if self.is_output() {
+                let temp = self.temp_ident();
+                quote!(#constraint(#temp))
+            } else if let Type::Infer(_) = register_type {
+                quote!(#constraint(<#rust_type as ::rusty_asm_runtime::AsmOperand>::to_register(#ident)))
+            } else if is_bool(rust_type) {
+                quote!(#constraint(#ident as #register_type))
+            } else {
+                quote!(#constraint(::core::mem::transmute::<#rust_type, #register_type>(#ident)))
+            };
+        }
+        match self.part {
+            None => quote!(#constraint(#ident)),
+            // The ASM code may write through a pointer to a mutable slice, so it can't come from a shared reborrow.
+            Some(Part::Ptr) if self.points_to_mut => quote!(#constraint(#ident.as_mut_ptr())),
+            Some(Part::Ptr) => quote!(#constraint(#ident.as_ptr())),
+            Some(Part::Len) => quote!(#constraint(#ident.len())),
+            // A wide output goes to a temporary variable until it can be reassembled. (See `AsmBlock::to_tokens`.)
+            Some(_) if self.is_output() => {
+                let temp = self.temp_ident();
+                quote!(#constraint(#temp))
+            },
+            Some(Part::Lo(wide_type)) => {
+                let half = wide_type.half();
+                quote!(#constraint(#ident as #half))
+            },
+            Some(Part::Hi(wide_type)) => {
+                let (unsigned, half, bits) = (wide_type.unsigned(), wide_type.half(), wide_type.half_bits());
+                quote!(#constraint(((#ident as #unsigned) >> #bits) as #half))
+            }
+        }
+    }
+
+    // Returns what the ASM code should use to refer to this operand, which is at the given position, without the `$`
+    fn reference(&self, index: usize) -> String {
+        match self.modifier {
+            Some(modifier) => format!("{{{}:{}}}", index, modifier),
+            None => index.to_string()
+        }
+    }
+
+    fn is_output(&self) -> bool {
+        self.llvm_constraint.0.starts_with('=')
+    }
+
+    // The temporary variable that holds one half of a wide output, or a flag output before it becomes a `bool`, or a
+    // converted output before it's converted back
+    fn temp_ident(&self) -> Ident {
+        Ident::new(&format!("__rusty_asm_{}", self.name().replace('.', "_")), self.ident.span())
+    }
+
+    // The name that the ASM code uses to refer to this operand, like `foo` or `buf.ptr`
+    fn name(&self) -> String {
+        match self.part {
+            None => self.ident.to_string(),
+            Some(ref part) => format!("{}.{}", self.ident, part.name())
+        }
     }
 
     fn bad_duplicate_of(&self, other: &Self) -> bool {
         // Removing duplicate identifiers is a matter of memory safety--it's dangerous (and maybe disallowed by the
         // compiler) to have two output registers linked to the same Rust variable.
-        format!("{}", self.ident) == format!("{}", other.ident)
+        self.name() == other.name()
     }
 
     // Returns the name of the explicit register referenced by this variable's constraint, if any.
-    // For instance, with a constraint of `"{eax}"`, it returns `"eax"`.
+    // For instance, with a constraint of `"{eax}"` or `"={eax}"`, it returns `"eax"`.
     pub fn explicit_register(&self) -> Option<&str> {
-        let constraint = self.llvm_constraint.0.as_str();
-        if constraint.starts_with('{') && constraint.ends_with('}') {
-            Some(&constraint[1 .. constraint.len() - 1])
-        } else {
-            None
-        }
+        registers::explicit_register(self.llvm_constraint.0.as_str())
+    }
+
+    // Like `explicit_register`, but an input that's tied to an output (as with `inout`) is in the output's register.
+    pub fn register<'a>(&'a self, bridge_vars_out: &'a [BridgeVar]) -> Option<&'a str> {
+        self.explicit_register().or_else(|| {
+            self.constraint_as_str().parse::<usize>().ok()
+                .and_then(|index| bridge_vars_out.get(index))
+                .and_then(BridgeVar::explicit_register)
+        })
     }
 
     pub fn constraint_as_str(&self) -> &str {
         self.llvm_constraint.0.as_str()
     }
 
+    // Determines whether the compiler chooses which general-purpose register the operand is in.
+    fn in_chosen_register(&self) -> bool {
+        self.explicit_register().is_none() && self.flag_condition.is_none()
+            && RegisterClass::of_constraint(self.constraint_as_str()).map_or(false, |class| class.name == "reg")
+    }
+
     pub fn constraint_span(&self) -> Span {
         self.llvm_constraint.1
     }
 }
 
+// One of the operands that a slice or a wide variable is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Ptr,
+    Len,
+    Lo(WideType),
+    Hi(WideType)
+}
+
+impl Part {
+    const NAMES: [&'static str; 4] = ["ptr", "len", "lo", "hi"];
+
+    fn name(self) -> &'static str {
+        match self {
+            Part::Ptr   => "ptr",
+            Part::Len   => "len",
+            Part::Lo(_) => "lo",
+            Part::Hi(_) => "hi"
+        }
+    }
+
+    // Parses the name of a part (like `.ptr`) at the start of the text, as long as it isn't the start of a longer
+    // identifier.
+    fn parse_suffix(text: &str) -> Option<&'static str> {
+        if !text.starts_with('.') {
+            return None;
+        }
+        let rest = &text[1 ..];
+        let name = Part::NAMES.iter().cloned().find(|name| rest.starts_with(name))?;
+        match rest[name.len() ..].chars().next() {
+            Some(c) if UnicodeXID::is_xid_continue(c) => None,
+            _ => Some(name)
+        }
+    }
+}
+
+// An integer type that's split across two registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WideType {
+    U64,
+    I64,
+    U128,
+    I128
+}
+
+impl WideType {
+    fn of(ty: &Type) -> Option<WideType> {
+        let path = match ty {
+            Type::Path(path) if path.qself.is_none() => &path.path,
+            _ => return None
+        };
+        [WideType::U64, WideType::I64, WideType::U128, WideType::I128].iter().cloned()
+            .find(|wide_type| path.is_ident(wide_type.name()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WideType::U64  => "u64",
+            WideType::I64  => "i64",
+            WideType::U128 => "u128",
+            WideType::I128 => "i128"
+        }
+    }
+
+    // The unsigned type of the same width, which is used to split the value without sign extension
+    fn unsigned(self) -> Ident {
+        match self {
+            WideType::U64 | WideType::I64   => Ident::new("u64", Span::call_site()),
+            WideType::U128 | WideType::I128 => Ident::new("u128", Span::call_site())
+        }
+    }
+
+    // The type of each half
+    fn half(self) -> Ident {
+        match self {
+            WideType::U64 | WideType::I64   => Ident::new("u32", Span::call_site()),
+            WideType::U128 | WideType::I128 => Ident::new("u64", Span::call_site())
+        }
+    }
+
+    fn half_bits(self) -> proc_macro2::Literal {
+        match self {
+            WideType::U64 | WideType::I64   => proc_macro2::Literal::u32_unsuffixed(32),
+            WideType::U128 | WideType::I128 => proc_macro2::Literal::u32_unsuffixed(64)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Clobber {
-    llvm_constraint: (String, Span)
+    llvm_constraint: (String, Span),
+    // The configurations that the clobber exists in, if it doesn't exist in all of them
+    cfg: Option<Condition>
 }
 
 impl Clobber {
@@ -684,30 +2873,266 @@ impl PartialEq for Clobber {
 
 impl Eq for Clobber {}
 
-impl Hash for Clobber {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.llvm_constraint.0.hash(state)
-    }
-}
-
 fn parenthesized(input: ParseStream) -> parse::Result<ParseBuffer> {
     let content;
     parenthesized!(content in input);
     Ok(content)
 }
 
-#[cfg(all(feature = "proc-macro", not(test)))]
-fn warn<T: Into<String>+Display>(span: Span, message: T) {
-    span.unstable().warning(message).emit();
+// Produces an error for every target that reserves the register. We can't know the target when the macro is expanded,
+// so each error is only compiled on the target that it's about.
+fn reserved_register_errors(constraint: &LitStr, reg: &str, help_message: &str) -> TokenStream {
+    let mut tokens = TokenStream::new();
+    for reserved in registers::reserved(reg) {
+        let target_arch = reserved.target_arch;
+        let message = format!("register `{}` can't be used on {}, since {}{}",
+            reg, target_arch, reserved.reason, diagnostics::help_suffix(help_message));
+        tokens.append_all(quote_spanned!(constraint.span()=> #[cfg(target_arch = #target_arch)] compile_error!(#message);));
+    }
+    tokens
 }
 
-#[cfg(not(all(feature = "proc-macro", not(test))))]
-fn warn<T: Into<String>+Display>(_: Span, _: T) {}
+// Produces an error for every target that doesn't have the register class.
+fn register_class_errors(class: &RegisterClass, span: Span) -> TokenStream {
+    if class.target_archs.is_empty() {
+        return TokenStream::new();
+    }
+    let target_archs = class.target_archs;
+    let message = format!("register class `{}` only exists on {}", class.name, target_archs.join(" or "));
+    // As with flag outputs, targets without inline ASM are left to the `asm` block.
+    let inline_asm_targets = registers::INLINE_ASM_TARGETS.iter();
+    quote_spanned!(span=>
+        #[cfg(all(any(#(target_arch = #inline_asm_targets),*), not(any(#(target_arch = #target_archs),*))))]
+        compile_error!(#message);
+    )
+}
 
-#[cfg(all(feature = "proc-macro", not(test)))]
-fn help<T: Into<String>+Display>(span: Span, message: T) {
-    span.unstable().help(message).emit();
+// Produces an error for every target that doesn't support the flag output's condition code, or any flag outputs at all.
+fn flag_condition_errors(condition: &LitStr) -> TokenStream {
+    let mut tokens = TokenStream::new();
+    for target in registers::FLAG_TARGETS.iter().filter(|target| !target.supports(&condition.value())) {
+        let target_archs = target.target_archs;
+        let message = format!("condition code `{}` doesn't exist on {}", condition.value(), target_archs.join(" or "));
+        tokens.append_all(quote_spanned!(condition.span()=>
+            #[cfg(any(#(target_arch = #target_archs),*))] compile_error!(#message);
+        ));
+    }
+    // Targets without inline ASM get an error from the `asm` block instead, unless it has a `fallback`.
+    let target_archs = registers::FLAG_TARGETS.iter().flat_map(|target| target.target_archs.iter());
+    let inline_asm_targets = registers::INLINE_ASM_TARGETS.iter();
+    tokens.append_all(quote_spanned!(condition.span()=>
+        #[cfg(all(any(#(target_arch = #inline_asm_targets),*), not(any(#(target_arch = #target_archs),*))))]
+        compile_error!("flag outputs aren't supported on this target");
+    ));
+    tokens
+}
+
+// Applies the levels of any of our lints that are named in an `allow`, `warn`, or `deny` attribute. Returns what's left
+// of the attribute for Rust to see, if anything.
+fn apply_lint_attribute(lints: &mut LintLevels, mut attr: Attribute) -> Option<Attribute> {
+    let mut list = match attr.parse_meta() {
+        Ok(Meta::List(list)) => list,
+        _ => return Some(attr)
+    };
+    let level = match Level::from_name(&list.ident.to_string()) {
+        Some(level) => level,
+        None => return Some(attr)
+    };
+
+    let mut others = Punctuated::<NestedMeta, Token![,]>::new();
+    for nested in list.nested.into_iter() {
+        match nested {
+            NestedMeta::Meta(Meta::Word(ref name)) if lints.set(name, level) => {},
+            other => others.push(other)
+        };
+    }
+    if others.is_empty() {
+        None
+    } else {
+        list.nested = others;
+        let nested = &list.nested;
+        attr.tts = quote!((#nested));
+        Some(attr)
+    }
+}
+
+fn is_mutable_pointer_type(ty: &Type) -> bool {
+    match ty {
+        Type::Ptr(ptr) => ptr.mutability.is_some(),
+        Type::Reference(reference) => reference.mutability.is_some(),
+        Type::Paren(paren) => is_mutable_pointer_type(&paren.elem),
+        Type::Group(group) => is_mutable_pointer_type(&group.elem),
+        _ => false
+    }
+}
+
+fn is_mutable_pointer_expr(expr: &Expr) -> bool {
+    match expr {
+        Expr::Reference(reference) => reference.mutability.is_some(),
+        Expr::Cast(cast) => is_mutable_pointer_type(&cast.ty),
+        Expr::MethodCall(call) => call.method == "as_mut_ptr",
+        Expr::Paren(paren) => is_mutable_pointer_expr(&paren.expr),
+        Expr::Group(group) => is_mutable_pointer_expr(&group.expr),
+        _ => false
+    }
+}
+
+fn is_bool(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path.qself.is_none() && path.path.is_ident("bool"),
+        _ => false
+    }
+}
+
+// Gives every token the same span, including the ones inside groups.
+fn respan(tokens: TokenStream, span: Span) -> TokenStream {
+    tokens.into_iter().map(|mut tt| {
+        if let TokenTree::Group(ref group) = tt {
+            let mut new_group = proc_macro2::Group::new(group.delimiter(), respan(group.stream(), span));
+            new_group.set_span(span);
+            tt = TokenTree::Group(new_group);
+        } else {
+            tt.set_span(span);
+        }
+        tt
+    }).collect()
+}
+
+// We only look for `asm` blocks at the statement level, so one in tokens that are passed on to Rust as they are (like
+// inside parentheses or an `if` condition) would never be expanded. This reports one that yields outputs, since it can
+// only be meant as an `asm` block.
+fn check_nested_asm_expressions(tokens: TokenStream, errors: &mut Vec<parse::Error>) {
+    if let Some(span) = nested_asm_expression(tokens) {
+        errors.push(error_with_help(span, "an `asm` expression has to be at the statement level",
+            "assign its value to a variable in a `let` statement first, and use that instead"));
+    }
 }
 
-#[cfg(not(all(feature = "proc-macro", not(test))))]
-fn help<T: Into<String>+Display>(_: Span, _: T) {}
+// Finds an `asm` block that yields outputs anywhere in the tokens, like `asm -> out("r") { ... }`, and returns the span
+// of its `asm` keyword.
+fn nested_asm_expression(tokens: TokenStream) -> Option<Span> {
+    let tts = tokens.into_iter().collect::<Vec<_>>();
+    let is_arrow = |i: usize| match (tts.get(i), tts.get(i + 1)) {
+        (Some(TokenTree::Punct(ref minus)), Some(TokenTree::Punct(ref gt))) => {
+            minus.as_char() == '-' && minus.spacing() == Spacing::Joint && gt.as_char() == '>'
+        },
+        _ => false
+    };
+    tts.iter().enumerate().find_map(|(i, tt)| match tt {
+        TokenTree::Ident(ref ident) if ident == "asm" => {
+            let options = matches!(tts.get(i + 1), Some(TokenTree::Group(ref group))
+                if group.delimiter() == Delimiter::Parenthesis);
+            if is_arrow(i + 1) || (options && is_arrow(i + 2)) { Some(ident.span()) } else { None }
+        },
+        TokenTree::Group(ref group) => nested_asm_expression(group.stream()),
+        _ => None
+    })
+}
+
+// Determines whether the identifier appears anywhere in the tokens, including inside groups.
+fn mentions(tokens: TokenStream, name: &str) -> bool {
+    tokens.into_iter().any(|tt| match tt {
+        TokenTree::Ident(ref ident) => ident == name,
+        TokenTree::Group(ref group) => mentions(group.stream(), name),
+        _ => false
+    })
+}
+
+// Determines whether a pattern binds the variable with the given name, as in `(a, x)` or `Point { x, .. }`. A name
+// that's part of a path (`x::A` or `A::x`), a constructor (`x(..)`), or a field that's bound to something else
+// (`Point { x: a }`) isn't bound.
+fn binds(pattern: TokenStream, name: &str) -> bool {
+    let tts = pattern.into_iter().collect::<Vec<_>>();
+    let is_colon = |tt: Option<&TokenTree>| match tt {
+        Some(TokenTree::Punct(punct)) => punct.as_char() == ':',
+        _ => false
+    };
+    tts.iter().enumerate().any(|(i, tt)| match tt {
+        TokenTree::Ident(ident) if ident == name => {
+            let (previous, next) = (i.checked_sub(1).and_then(|i| tts.get(i)), tts.get(i + 1));
+            !is_colon(previous) && !is_colon(next) && !matches!(next, Some(TokenTree::Group(_)))
+        },
+        TokenTree::Group(group) => binds(group.stream(), name),
+        _ => false
+    })
+}
+
+// Determines whether the tokens assign to the variable with the given name, as in `x = ...` or `x += ...`.
+fn assigns(tokens: TokenStream, ident: &Ident) -> bool {
+    const OPERATORS: [&str; 11] = ["=", "+=", "-=", "*=", "/=", "%=", "^=", "&=", "|=", "<<=", ">>="];
+    let tts = tokens.into_iter().collect::<Vec<_>>();
+    tts.iter().enumerate().any(|(i, tt)| match tt {
+        TokenTree::Ident(ref other) if other == ident => {
+            let mut operator = String::new();
+            for tt in tts[i + 1 ..].iter() {
+                match tt {
+                    TokenTree::Punct(ref punct) => {
+                        operator.push(punct.as_char());
+                        if punct.spacing() == Spacing::Alone {
+                            break;
+                        }
+                    },
+                    _ => break
+                }
+            }
+            OPERATORS.contains(&operator.as_str())
+        },
+        TokenTree::Group(ref group) => assigns(group.stream(), ident),
+        _ => false
+    })
+}
+
+// Makes sure there's nothing left in a delimited group. Otherwise, syn would only notice the leftover tokens when it
+// finished parsing the whole invocation, and it would report them as the only error.
+fn expect_end(input: ParseStream) -> parse::Result<()> {
+    if input.is_empty() {
+        Ok(())
+    } else {
+        Err(input.error("unexpected token"))
+    }
+}
+
+// Skips the given number of token trees (or as many as are left, if that's fewer).
+fn skip_token_trees(input: ParseStream, count: usize) {
+    let _ = input.step(|cursor| {
+        let mut rest = *cursor;
+        for _ in 0 .. count {
+            match rest.token_tree() {
+                Some((_, next)) => rest = next,
+                None => break
+            };
+        }
+        Ok(((), rest))
+    });
+}
+
+// Skips everything up to and including the next block (or up to the end of the block we're in if there isn't one).
+fn skip_through_block(input: ParseStream) {
+    let _ = input.step(|cursor| {
+        let mut rest = *cursor;
+        while let Some((tt, next)) = rest.token_tree() {
+            rest = next;
+            match tt {
+                TokenTree::Group(ref group) if group.delimiter() == Delimiter::Brace => break,
+                _ => {}
+            };
+        }
+        Ok(((), rest))
+    });
+}
+
+// Skips everything up to and including the next semicolon (or up to the end of the block if there isn't one), so that
+// parsing can resume at the next statement after an error.
+fn skip_statement(input: ParseStream) {
+    let _ = input.step(|cursor| {
+        let mut rest = *cursor;
+        while let Some((tt, next)) = rest.token_tree() {
+            rest = next;
+            match tt {
+                TokenTree::Punct(ref punct) if punct.as_char() == ';' => break,
+                _ => {}
+            };
+        }
+        Ok(((), rest))
+    });
+}
@@ -0,0 +1,544 @@
+// Copyright (c) 2018 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Knowledge about the registers of specific targets, for checks that can't be done just by comparing names, like
+//! noticing that `al` and `eax` are parts of the same register.
+
+/// A register, or part of one, that can be named in a constraint or a clobber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    /// The name of the whole register (e.g. `rax` for `al`)
+    pub full_name: &'static str,
+    // The parts of the whole register that this name refers to, as a bit mask over eighths of it (bit 0 is the lowest
+    // eighth, which is one byte of a general-purpose register on x86-64)
+    bytes: u8
+}
+
+// The general-purpose registers of x86-64. The first 8 also have the older names that x86 uses.
+const X86_REGISTERS: [&str; 16] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"
+];
+
+// The full names of the vector registers of x86 and AArch64, which `Register` needs as `&'static str`s
+const ALL_VECTOR_REGISTERS: [&str; 64] = [
+    "zmm0", "zmm1", "zmm2", "zmm3", "zmm4", "zmm5", "zmm6", "zmm7",
+    "zmm8", "zmm9", "zmm10", "zmm11", "zmm12", "zmm13", "zmm14", "zmm15",
+    "zmm16", "zmm17", "zmm18", "zmm19", "zmm20", "zmm21", "zmm22", "zmm23",
+    "zmm24", "zmm25", "zmm26", "zmm27", "zmm28", "zmm29", "zmm30", "zmm31",
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7",
+    "v8", "v9", "v10", "v11", "v12", "v13", "v14", "v15",
+    "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
+    "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31"
+];
+
+impl Register {
+    /// Looks up a register by any of its names. Returns `None` if the register isn't known.
+    pub fn lookup(name: &str) -> Option<Register> {
+        let name = name.to_ascii_lowercase();
+        if let Some(register) = Self::lookup_vector(&name) {
+            return Some(register);
+        }
+        for (i, &full_name) in X86_REGISTERS.iter().enumerate() {
+            let bytes = if i < 8 {
+                // e.g. `rax`, `eax`, `ax`, `al`, `ah` or `rsi`, `esi`, `si`, `sil`
+                let base = &full_name[1 ..];
+                let legacy_byte = &base[.. 1];
+                if name == full_name {
+                    0xff
+                } else if strip_prefix(&name, "e") == Some(base) {
+                    0x0f
+                } else if name == base {
+                    0x03
+                } else if i < 4 && strip_prefix(&name, legacy_byte) == Some("l") {
+                    0x01
+                } else if i < 4 && strip_prefix(&name, legacy_byte) == Some("h") {
+                    0x02
+                } else if i >= 4 && strip_prefix(&name, base) == Some("l") {
+                    0x01
+                } else {
+                    continue;
+                }
+            } else {
+                // e.g. `r8`, `r8d`, `r8w`, `r8b` (or `r8l`)
+                match strip_prefix(&name, full_name) {
+                    Some("")              => 0xff,
+                    Some("d")             => 0x0f,
+                    Some("w")             => 0x03,
+                    Some("b") | Some("l") => 0x01,
+                    _                     => continue
+                }
+            };
+            return Some(Register { full_name, bytes });
+        }
+        None
+    }
+
+    // Looks up a vector register: `xmm<n>`, `ymm<n>`, or `zmm<n>` on x86, or `v<n>`, `q<n>`, or `d<n>` on AArch64. Each
+    // group of names shares the same registers, named after the widest one.
+    fn lookup_vector(name: &str) -> Option<Register> {
+        const PREFIXES: [(&str, &str, u8); 6] = [
+            ("zmm", "zmm", 0xff),
+            ("ymm", "zmm", 0x0f),
+            ("xmm", "zmm", 0x03),
+            ("v",   "v",   0xff),
+            ("q",   "v",   0xff),
+            ("d",   "v",   0x0f)
+        ];
+        PREFIXES.iter().find_map(|&(prefix, family, bytes)| {
+            let number = strip_prefix(name, prefix)?.parse::<u8>().ok()?;
+            let full_name = ALL_VECTOR_REGISTERS.iter().cloned()
+                .find(|full_name| strip_prefix(full_name, family).and_then(|n| n.parse::<u8>().ok()) == Some(number))?;
+            Some(Register { full_name, bytes })
+        })
+    }
+
+    /// Determines whether the two registers share any bits.
+    pub fn overlaps(self, other: Register) -> bool {
+        self.full_name == other.full_name && self.bytes & other.bytes != 0
+    }
+
+    /// Determines whether every bit of `other` is also part of this register.
+    pub fn covers(self, other: Register) -> bool {
+        self.full_name == other.full_name && other.bytes & !self.bytes == 0
+    }
+}
+
+/// Determines whether the two named registers share any bits. Registers we don't know about only overlap if they have
+/// the same name.
+pub fn overlap(a: &str, b: &str) -> bool {
+    match (Register::lookup(a), Register::lookup(b)) {
+        (Some(a), Some(b)) => a.overlaps(b),
+        _ => a.eq_ignore_ascii_case(b)
+    }
+}
+
+/// Determines whether every bit of register `inner` is also part of register `outer`. Registers we don't know about
+/// only cover each other if they have the same name.
+pub fn covers(outer: &str, inner: &str) -> bool {
+    match (Register::lookup(outer), Register::lookup(inner)) {
+        (Some(outer), Some(inner)) => outer.covers(inner),
+        _ => outer.eq_ignore_ascii_case(inner)
+    }
+}
+
+/// Returns the name of the explicit register referenced by a constraint, if any. For instance, with a constraint of
+/// `"{eax}"` or `"={eax}"`, it returns `"eax"`.
+pub fn explicit_register(constraint: &str) -> Option<&str> {
+    let constraint = constraint.trim_start_matches(&['=', '+', '&'][..]);
+    if constraint.starts_with('{') && constraint.ends_with('}') {
+        Some(&constraint[1 .. constraint.len() - 1])
+    } else {
+        None
+    }
+}
+
+// Returns the rest of the name if it starts with the prefix. (`str::strip_prefix` is too new for the compilers that
+// support the `asm!` syntax we generate.)
+fn strip_prefix<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+    if name.starts_with(prefix) {
+        Some(&name[prefix.len() ..])
+    } else {
+        None
+    }
+}
+
+/// A register that can't be used in constraints or clobbers on a particular target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedRegister {
+    /// The `target_arch` that reserves the register
+    pub target_arch: &'static str,
+    /// Why it's reserved
+    pub reason: &'static str
+}
+
+// Every register that LLVM reserves or that the platform needs to stay intact, with all of its names, by target
+const RESERVED_REGISTERS: [(&str, &[&str], &str); 23] = [
+    ("x86_64",  &["rsp", "esp", "sp", "spl"],       "it's the stack pointer"),
+    ("x86_64",  &["rbp", "ebp", "bp", "bpl"],       "it's the frame pointer"),
+    ("x86_64",  &["rbx", "ebx", "bx", "bl", "bh"],  "LLVM uses it internally"),
+    ("x86",     &["esp", "sp"],                     "it's the stack pointer"),
+    ("x86",     &["ebp", "bp"],                     "it's the frame pointer"),
+    ("x86",     &["esi", "si"],                     "LLVM uses it internally"),
+    ("aarch64", &["xzr", "wzr"],                    "it's always zero"),
+    ("aarch64", &["sp", "wsp"],                     "it's the stack pointer"),
+    ("aarch64", &["x18", "w18"],                    "it's the platform register, which some operating systems reserve"),
+    ("aarch64", &["x19", "w19"],                    "LLVM uses it internally"),
+    ("aarch64", &["x29", "w29", "fp"],              "it's the frame pointer"),
+    ("riscv32", &["zero", "x0"],                    "it's always zero"),
+    ("riscv32", &["sp", "x2"],                      "it's the stack pointer"),
+    ("riscv32", &["gp", "x3"],                      "it's the global pointer"),
+    ("riscv32", &["tp", "x4"],                      "it's the thread pointer"),
+    ("riscv32", &["s0", "x8", "fp"],                "it's the frame pointer"),
+    ("riscv32", &["s1", "x9"],                      "LLVM uses it internally"),
+    ("riscv64", &["zero", "x0"],                    "it's always zero"),
+    ("riscv64", &["sp", "x2"],                      "it's the stack pointer"),
+    ("riscv64", &["gp", "x3"],                      "it's the global pointer"),
+    ("riscv64", &["tp", "x4"],                      "it's the thread pointer"),
+    ("riscv64", &["s0", "x8", "fp"],                "it's the frame pointer"),
+    ("riscv64", &["s1", "x9"],                      "LLVM uses it internally")
+];
+
+/// Returns every target that reserves the named register. The same name can mean different things on different
+/// targets, and the target isn't known until the code is compiled, so all of them are returned.
+pub fn reserved(name: &str) -> Vec<ReservedRegister> {
+    let name = name.to_ascii_lowercase();
+    RESERVED_REGISTERS.iter()
+        .filter(|(_, names, _)| names.contains(&name.as_str()))
+        .map(|&(target_arch, _, reason)| ReservedRegister { target_arch, reason })
+        .collect()
+}
+
+/// Every target that Rust supports inline ASM on. An `asm` block can't be compiled anywhere else, so only its `fallback`
+/// is used there.
+pub const INLINE_ASM_TARGETS: [&str; 18] = [
+    "x86", "x86_64", "arm", "aarch64", "riscv32", "riscv64", "loongarch64", "s390x", "powerpc", "powerpc64", "mips",
+    "mips64", "sparc", "sparc64", "hexagon", "nvptx64", "avr", "msp430"
+];
+
+/// The condition codes that flag outputs can test on a family of targets, and how to test one when the compiler can't
+/// produce flag outputs by itself
+#[derive(Debug, Clone, Copy)]
+pub struct FlagTarget {
+    /// The `target_arch`s in the family
+    pub target_archs: &'static [&'static str],
+    /// Every condition code that can be tested, like `z` or `eq`
+    pub conditions: &'static [&'static str],
+    /// The constraint for the register that `fallback_instruction` writes to
+    pub fallback_constraint: &'static str,
+    // Returns an instruction that sets the numbered operand to 1 if the condition holds, or to 0 if it doesn't
+    fallback_instruction: fn(&str, usize) -> String
+}
+
+/// Every family of targets that supports flag outputs
+pub const FLAG_TARGETS: [FlagTarget; 2] = [
+    FlagTarget {
+        target_archs: &["x86", "x86_64"],
+        conditions: &[
+            "a", "ae", "b", "be", "c", "e", "g", "ge", "l", "le", "na", "nae", "nb", "nbe", "nc", "ne", "ng", "nge",
+            "nl", "nle", "no", "np", "ns", "nz", "o", "p", "pe", "po", "s", "z"
+        ],
+        // `setcc` needs a register with an addressable low byte, which only `a`, `b`, `c`, and `d` have on x86.
+        fallback_constraint: "=q",
+        fallback_instruction: |condition, operand| format!("set{} ${}", condition, operand)
+    },
+    FlagTarget {
+        target_archs: &["aarch64"],
+        conditions: &["eq", "ne", "cs", "hs", "cc", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le"],
+        fallback_constraint: "=r",
+        fallback_instruction: |condition, operand| format!("cset ${}, {}", operand, condition)
+    }
+];
+
+impl FlagTarget {
+    /// Determines whether the condition code can be tested on this family of targets.
+    pub fn supports(&self, condition: &str) -> bool {
+        self.conditions.contains(&condition)
+    }
+
+    /// Returns an instruction that stores the result of testing the condition in the numbered operand, which is bound
+    /// with `fallback_constraint`.
+    pub fn fallback_instruction(&self, condition: &str, operand: usize) -> String {
+        (self.fallback_instruction)(condition, operand)
+    }
+}
+
+/// How to tell Rust code which label the ASM code took on a family of targets. Rust's `asm!` can't jump out of
+/// the ASM code, so each label is a stub at the end of it that stores the label's number in an output.
+#[derive(Debug, Clone, Copy)]
+pub struct LabelTarget {
+    /// The `target_arch`s in the family
+    pub target_archs: &'static [&'static str],
+    // Returns an instruction that sets the numbered operand to a value, given whether the code uses Intel syntax
+    set_instruction: fn(usize, usize, bool) -> String,
+    // Returns an instruction that jumps to a label
+    jump_instruction: fn(&str) -> String
+}
+
+/// Every family of targets that `asm` blocks can have labels on
+pub const LABEL_TARGETS: [LabelTarget; 4] = [
+    LabelTarget {
+        target_archs: &["x86", "x86_64"],
+        set_instruction: |operand, value, intel| if intel {
+            format!("mov ${}, {}", operand, value)
+        } else {
+            format!("mov $${}, ${}", value, operand)
+        },
+        jump_instruction: |label| format!("jmp {}", label)
+    },
+    LabelTarget {
+        target_archs: &["arm"],
+        set_instruction: |operand, value, _| format!("mov ${}, #{}", operand, value),
+        jump_instruction: |label| format!("b {}", label)
+    },
+    LabelTarget {
+        target_archs: &["aarch64"],
+        set_instruction: |operand, value, _| format!("mov ${}, #{}", operand, value),
+        jump_instruction: |label| format!("b {}", label)
+    },
+    LabelTarget {
+        target_archs: &["riscv32", "riscv64"],
+        set_instruction: |operand, value, _| format!("li ${}, {}", operand, value),
+        jump_instruction: |label| format!("j {}", label)
+    }
+];
+
+impl LabelTarget {
+    /// Returns the ASM code that goes after the code of a block with the given number of labels. It stores the number
+    /// of the label that the code jumped to (counting from 1), or 0 if it didn't jump, in the numbered operand.
+    pub fn epilogue(&self, operand: usize, labels: usize, intel: bool) -> String {
+        let end = "${:private}__rusty_asm_end_${:uid}";
+        let stub = |value: usize| format!("\n\t{}\n\t{}", (self.set_instruction)(operand, value, intel),
+            (self.jump_instruction)(end));
+        let mut code = stub(0);
+        for index in 0 .. labels {
+            code.push_str(&format!("\n{}:{}", label_name(index), stub(index + 1)));
+        }
+        code.push_str(&format!("\n{}:", end));
+        code
+    }
+}
+
+/// Returns the name that the ASM code uses for the numbered label. It's local to the `asm!` invocation.
+pub fn label_name(index: usize) -> String {
+    format!("${{:private}}__rusty_asm_label_{}_${{:uid}}", index)
+}
+
+/// A class of registers that a bridge variable can be bound to by name, like `in(xmm_reg)`, along with the `core::arch`
+/// types that fit in it
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterClass {
+    /// The name that's used in a declaration
+    pub name: &'static str,
+    /// The name of the matching variant of `rusty_asm_runtime::RegisterClass`
+    pub runtime_name: &'static str,
+    /// The `target_arch`s that have the class, or nothing if every target has it
+    pub target_archs: &'static [&'static str],
+    /// The LLVM constraint that selects a register in the class
+    pub llvm_constraint: &'static str,
+    /// The modifier that the ASM code needs in order to see the register under the class's name, if any (e.g. `q` to
+    /// make LLVM print `q0` instead of `v0`)
+    pub modifier: Option<&'static str>,
+    /// The names of the types that fit in the class's registers, in groups. If there aren't any, the types can't be
+    /// checked.
+    pub types: &'static [&'static [&'static str]]
+}
+
+const NEON_64_BIT_TYPES: [&str; 13] = [
+    "int8x8_t", "uint8x8_t", "poly8x8_t", "int16x4_t", "uint16x4_t", "poly16x4_t", "int32x2_t", "uint32x2_t",
+    "float32x2_t", "int64x1_t", "uint64x1_t", "poly64x1_t", "float64x1_t"
+];
+
+const NEON_128_BIT_TYPES: [&str; 13] = [
+    "int8x16_t", "uint8x16_t", "poly8x16_t", "int16x8_t", "uint16x8_t", "poly16x8_t", "int32x4_t", "uint32x4_t",
+    "float32x4_t", "int64x2_t", "uint64x2_t", "poly64x2_t", "float64x2_t"
+];
+
+/// Every register class that can be named in a declaration
+pub const REGISTER_CLASSES: [RegisterClass; 9] = [
+    RegisterClass {
+        name: "reg", runtime_name: "Reg", target_archs: &[], llvm_constraint: "r", modifier: None,
+        types: &[]
+    },
+    RegisterClass {
+        name: "xmm_reg", runtime_name: "XmmReg", target_archs: &["x86", "x86_64"], llvm_constraint: "x", modifier: None,
+        types: &[&["__m128", "__m128i", "__m128d"]]
+    },
+    RegisterClass {
+        name: "ymm_reg", runtime_name: "YmmReg", target_archs: &["x86", "x86_64"], llvm_constraint: "x", modifier: None,
+        types: &[&["__m256", "__m256i", "__m256d"]]
+    },
+    // `x` only covers the first 16 registers, and AVX-512 adds 16 more.
+    RegisterClass {
+        name: "zmm_reg", runtime_name: "ZmmReg", target_archs: &["x86", "x86_64"], llvm_constraint: "v", modifier: None,
+        types: &[&["__m512", "__m512i", "__m512d"]]
+    },
+    RegisterClass {
+        name: "kreg", runtime_name: "KReg", target_archs: &["x86", "x86_64"], llvm_constraint: "k", modifier: None,
+        types: &[&["__mmask8", "__mmask16", "__mmask32", "__mmask64"]]
+    },
+    // A `v` register can hold a 64-bit vector in its low half.
+    RegisterClass {
+        name: "vreg", runtime_name: "VReg", target_archs: &["aarch64"], llvm_constraint: "w", modifier: None,
+        types: &[&NEON_64_BIT_TYPES, &NEON_128_BIT_TYPES]
+    },
+    RegisterClass {
+        name: "qreg", runtime_name: "QReg", target_archs: &["aarch64"], llvm_constraint: "w", modifier: Some("q"),
+        types: &[&NEON_128_BIT_TYPES]
+    },
+    RegisterClass {
+        name: "dreg", runtime_name: "DReg", target_archs: &["aarch64"], llvm_constraint: "w", modifier: Some("d"),
+        types: &[&NEON_64_BIT_TYPES]
+    },
+    // `core::arch` doesn't have any types for the RISC-V vector extension yet.
+    RegisterClass {
+        name: "rvv_reg", runtime_name: "RvvReg", target_archs: &["riscv32", "riscv64"], llvm_constraint: "vr", modifier: None,
+        types: &[]
+    }
+];
+
+impl RegisterClass {
+    /// Looks up a register class by the name that's used in declarations.
+    pub fn lookup(name: &str) -> Option<&'static RegisterClass> {
+        REGISTER_CLASSES.iter().find(|class| class.name == name)
+    }
+
+    /// Returns the class that an explicit register belongs to, as long as its name can only mean that register. (That
+    /// rules out `v0`, for instance, which is also a RISC-V vector register.)
+    pub fn of_register(name: &str) -> Option<&'static RegisterClass> {
+        const PREFIXES: [(&str, &str, u8); 5] = [
+            ("xmm", "xmm_reg", 32),
+            ("ymm", "ymm_reg", 32),
+            ("zmm", "zmm_reg", 32),
+            ("k",   "kreg",    8),
+            ("q",   "qreg",    32)
+        ];
+        let name = name.to_ascii_lowercase();
+        let class_name = PREFIXES.iter()
+            .find(|&&(prefix, _, count)| {
+                strip_prefix(&name, prefix).and_then(|n| n.parse::<u8>().ok()).map_or(false, |n| n < count)
+            })
+            .map(|&(_, class_name, _)| class_name)?;
+        Self::lookup(class_name)
+    }
+
+    /// Returns the class of the registers that a constraint can select, if it's known. That includes general-purpose
+    /// register constraints like `"r"` and explicit registers like `"{eax}"` or `"{xmm0}"`. (Constraints like `"x"`
+    /// aren't included, since the register's width depends on the operand's type.)
+    pub fn of_constraint(constraint: &str) -> Option<&'static RegisterClass> {
+        if let Some(register) = explicit_register(constraint) {
+            return Self::of_register(register).or_else(|| {
+                Register::lookup(register).filter(|register| !register.full_name.starts_with(&['z', 'v'][..]))
+                    .and_then(|_| Self::lookup("reg"))
+            });
+        }
+        match constraint.trim_start_matches(&['=', '+', '&'][..]) {
+            "r" | "q" | "Q" | "R" | "l" | "a" | "b" | "c" | "d" | "S" | "D" => Self::lookup("reg"),
+            "k" => Self::lookup("kreg"),
+            _ => None
+        }
+    }
+
+    /// Determines whether a type with the given name fits in the class's registers. Any type fits if the class doesn't
+    /// list its types.
+    pub fn fits(&self, type_name: &str) -> bool {
+        self.types.is_empty() || self.types.iter().any(|group| group.contains(&type_name))
+    }
+
+    /// Lists the types that fit in the class's registers, for error messages.
+    pub fn type_list(&self) -> String {
+        self.types.iter().flat_map(|group| group.iter()).map(|name| format!("`{}`", name)).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// How to take snapshots of the general-purpose registers of a target, for `asm` blocks with the `checked` option
+///
+/// A snapshot is stored in a buffer of `3 * registers.len()` words: the registers' values before the ASM code, their
+/// values after it, and a nonzero word for each register that the compiler chose for an output. The buffer's address
+/// is passed in memory, in a local variable on the stack, where the ASM code can't change it by accident. Each snapshot
+/// swaps it into `address_register` and swaps the register's own value back afterward, so the snapshot doesn't change
+/// any register, and `address_register` is checked like the others.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckedTarget {
+    pub target_arch: &'static str,
+    /// Every general-purpose register, including the stack pointer
+    pub registers: &'static [&'static str],
+    /// The operand modifier that makes a register operand print with the full width of a general-purpose register
+    pub modifier: &'static str,
+    /// The register that holds the buffer's address while a snapshot is taken
+    pub address_register: &'static str,
+    word_size: usize,
+    // The suffix that AT&T syntax gives instructions that work with whole registers
+    suffix: &'static str
+}
+
+/// Every target that supports the `checked` option
+pub const CHECKED_TARGETS: [CheckedTarget; 2] = [
+    CheckedTarget {
+        target_arch: "x86_64",
+        registers: &X86_REGISTERS,
+        modifier: "q",
+        address_register: "rdi",
+        word_size: 8,
+        suffix: "q"
+    },
+    CheckedTarget {
+        target_arch: "x86",
+        registers: &["eax", "ebx", "ecx", "edx", "esi", "edi", "ebp", "esp"],
+        modifier: "k",
+        address_register: "edi",
+        word_size: 4,
+        suffix: "l"
+    }
+];
+
+impl CheckedTarget {
+    /// Returns ASM code that saves every register in the first (`after == false`) or second part of the buffer whose
+    /// address is in the numbered memory operand. If the code comes after the ASM code, it also marks the registers
+    /// that hold the numbered outputs, which have to be bound with `modifier`.
+    pub fn snapshot(&self, after: bool, outputs: &[usize], address_operand: usize, intel: bool) -> String {
+        let count = self.registers.len();
+        let offset = |index: usize, part: usize| (part * count + index) * self.word_size;
+        let part = if after { 1 } else { 0 };
+        let address = self.address_register;
+        let address_index = self.registers.iter().position(|&register| register == address).unwrap();
+        let slot = format!("${}", address_operand);
+        // The first register is borrowed to copy the address register's own value from the slot into the buffer, and
+        // then restored from the buffer.
+        let scratch = self.registers[0];
+
+        // `register(name)`, `memory(offset)`, `mov(destination, source)`, `xchg()`, and `mark(offset)`
+        let register = |name: &str| if intel { String::from(name) } else { format!("%{}", name) };
+        let memory = |offset: usize| if intel {
+            format!("[{} + {}]", address, offset)
+        } else {
+            format!("{}(%{})", offset, address)
+        };
+        let mov = |destination: &str, source: &str| if intel {
+            format!("mov {}, {}", destination, source)
+        } else {
+            format!("mov{} {}, {}", self.suffix, source, destination)
+        };
+        let xchg = || if intel {
+            format!("xchg {}, {}", address, slot)
+        } else {
+            format!("xchg{} %{}, {}", self.suffix, address, slot)
+        };
+        let mark = |offset: usize| if intel {
+            format!("mov {} ptr [{} + {}], 1", if self.word_size == 8 { "qword" } else { "dword" }, address, offset)
+        } else {
+            format!("mov{} $$1, {}(%{})", self.suffix, offset, address)
+        };
+
+        let mut lines = vec![xchg()];
+        lines.extend(self.registers.iter().enumerate()
+            .filter(|&(i, _)| i != address_index)
+            .map(|(i, name)| mov(&memory(offset(i, part)), &register(name))));
+        lines.push(mov(&register(scratch), &slot));
+        lines.push(mov(&memory(offset(address_index, part)), &register(scratch)));
+        lines.push(mov(&register(scratch), &memory(offset(0, part))));
+        if after {
+            // The operands are only known by the time the code is assembled, so the assembler compares their names.
+            for &output in outputs {
+                for (i, register) in self.registers.iter().enumerate() {
+                    lines.push(format!(".ifc ${{{}:{}}},{}{}", output, self.modifier, if intel { "" } else { "%" },
+                        register));
+                    lines.push(mark(offset(i, 2)));
+                    lines.push(String::from(".endif"));
+                }
+            }
+        }
+        lines.push(xchg());
+        lines.join("\n\t")
+    }
+}
@@ -134,6 +134,30 @@ fn bad_identifiers() {
     }
 }
 
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn lint_levels() {
+    unsafe {
+        rusty_asm! {
+            #![deny(rusty_asm)]
+            {
+                #![allow(unused_bridge_var)] // `x` is only referenced as `$0`.
+                let mut x: u32: inout("r") = 2;
+                // This block would fail to compile without the `allow`, since `$0` isn't a bridge variable.
+                asm("intel", allow(stray_dollar)) {
+                    "shl $0, 3"
+                }
+                assert_eq!(x, 2 << 3);
+            }
+            {
+                // This block would fail to compile without the `allow`, since `unused` isn't used.
+                #![allow(unused_bridge_var, unused_variables)]
+                let unused: in("r") = 0u32;
+            }
+        }
+    }
+}
+
 #[test]
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 fn inout_out_inout() {
@@ -191,6 +215,303 @@ fn clobber_overlap() {
     }
 }
 
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn wide_values() {
+    unsafe {
+        rusty_asm! {
+            // Add two 128-bit numbers, 64 bits at a time.
+            let mut a: u128: inout("r", "r") = (1 << 64) | u64::max_value() as u128;
+            let b: u128: in("r", "r") = 1;
+            asm {"
+                addq $b.lo, $a.lo
+                adcq $b.hi, $a.hi
+            "}
+            assert_eq!(a, 2 << 64);
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn flag_outputs() {
+    for &(a, b) in [(3u32, 3u32), (3, 4), (0, u32::max_value())].iter() {
+        unsafe {
+            rusty_asm! {
+                let a: in("r") = a;
+                let b: in("r") = b;
+                let equal: out(flag "e");
+                let below: bool: out(flag "b");
+                asm {
+                    "cmpl $b, $a"
+                }
+                assert_eq!(equal, a == b);
+                assert_eq!(below, a < b);
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn register_classes() {
+    use std::arch::x86_64::{__m128i, _mm_set_epi32};
+    use std::mem;
+
+    unsafe {
+        rusty_asm! {
+            let mut a: __m128i: inout(xmm_reg) = _mm_set_epi32(4, 3, 2, 1);
+            let b: __m128i: in(xmm_reg) = _mm_set_epi32(40, 30, 20, 10);
+            asm {
+                "paddd $b, $a"
+            }
+            assert_eq!(mem::transmute::<__m128i, [i32; 4]>(a), [11, 22, 33, 44]);
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn conversions() {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    enum Color {
+        Red = 1,
+        Green = 2
+    }
+
+    unsafe {
+        rusty_asm! {
+            let mut color: Color as u8: inout("r") = Color::Red;
+            let is_red: bool as u8: out("&r"); // Written before `flip` is read
+            let flip: bool as u8: in("r") = true;
+            asm {"
+                cmpb $$1, $color
+                sete $is_red
+                xorb $flip, $color
+                xorb $$2, $color
+            "}
+            assert_eq!(color, Color::Green);
+            assert!(is_red);
+        }
+    }
+}
+
+#[test]
+fn alternatives() {
+    for &x in [0u32, 41, u32::max_value() - 1].iter() {
+        unsafe {
+            rusty_asm! {
+                let x: in("r") = x;
+                let y: u32: out("r");
+                asm(target_arch = "x86_64") {
+                    "leal 1($x), $y"
+                } else asm(target_arch = "x86") {
+                    "leal 1($x), $y"
+                } else {
+                    y = x + 1;
+                }
+                assert_eq!(y, x + 1);
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn fallbacks() {
+    for &x in [0u32, 0x1234_5678, u32::max_value()].iter() {
+        unsafe {
+            rusty_asm! {
+                let mut y: inout("r") = x;
+                asm {
+                    "bswap $y"
+                } fallback {
+                    y = y.swap_bytes();
+                }
+                assert_eq!(y, x.swap_bytes());
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn checked() {
+    unsafe {
+        rusty_asm! {
+            let x: in("r") = 3u64;
+            let y: u64: out("r");
+            clobber("r11");
+            asm(checked) {"
+                movq $x, $y
+                movq $$5, %r11
+            "}
+            assert_eq!(y, 3);
+        }
+    }
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", debug_assertions))]
+#[should_panic(expected = "the ASM code changed `r11`, which isn't declared as an output or a clobber")]
+fn checked_undeclared() {
+    unsafe {
+        rusty_asm! {
+            // `r11` is changed without a clobber.
+            asm(checked) {
+                "movq $$7, %r11"
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn tracing() {
+    use std::cell::RefCell;
+    use std::fmt::Debug;
+
+    thread_local! {
+        static TRACE: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    fn hook(_location: &str, direction: &str, name: &str, value: &dyn Debug) {
+        TRACE.with(|trace| trace.borrow_mut().push(format!("{} {} = {:?}", direction, name, value)));
+    }
+
+    unsafe {
+        rusty_asm! {
+            let mut a: u32: inout("r") = 5;
+            let b: in("r") = 7u32;
+            asm(trace = hook) {
+                "addl $b, $a"
+            }
+            assert_eq!(a, 12);
+        }
+    }
+    if cfg!(debug_assertions) {
+        TRACE.with(|trace| assert_eq!(*trace.borrow(), ["in a = 5", "in b = 7", "out a = 12"]));
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inline_operands() {
+    let b = 10u32;
+    unsafe {
+        rusty_asm! {
+            let mut sum: inout("r") = 5u32;
+            asm {
+                "addl ${b + 1}, $sum"
+            }
+            assert_eq!(sum, 16);
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn yielded_outputs() {
+    unsafe {
+        rusty_asm! {
+            let (lo, hi): (u32, u32) = asm("volatile") -> (out("{eax}"), out("{edx}")) {
+                "rdtsc"
+            };
+            let before = (u64::from(hi) << 32) | u64::from(lo);
+            let (lo, hi): (u32, u32) = asm("volatile") -> (out("{eax}"), out("{edx}")) {
+                "rdtsc"
+            };
+            assert!((u64::from(hi) << 32) | u64::from(lo) >= before);
+
+            let doubled = asm -> out("r") doubled: u32 {
+                "leal (${lo}, ${lo}), $doubled"
+            };
+            assert_eq!(doubled, lo.wrapping_mul(2));
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn header_operands() {
+    let (a, b) = (3u32, 4u32);
+    unsafe {
+        rusty_asm! {
+            let mut total: inout("r") = 1u32;
+            asm(in("r") x = a + b, out("r") scratch: u32, "intel") {
+                "lea $scratch, [$x + $x]\n\tadd $total, $scratch"
+            }
+            assert_eq!(total, 15);
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cfg_attributes() {
+    unsafe {
+        rusty_asm! {
+            let mut x: inout("r") = 5u32;
+            #[cfg(test)]
+            let one: in("r") = 1u32;
+            #[cfg(not(test))]
+            let two: in("r") = 2u32;
+            #[cfg(test)]
+            asm {
+                "addl $one, $x"
+            }
+            #[cfg(not(test))]
+            asm {
+                "addl $two, $x"
+            }
+            #[cfg_attr(test, cfg(target_arch = "x86_64"))]
+            clobber("edx");
+            asm {
+                "shll $$1, $x"
+            }
+            assert_eq!(x, 12);
+        }
+    }
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn label_arms() {
+    fn checked_div(a: u32, b: u32) -> Option<u32> {
+        unsafe {
+            rusty_asm! {
+                let b: in("r") = b;
+                asm {
+                    "testl $b, $b\n\tjz $on_zero"
+                } on_zero => {
+                    return None;
+                }
+                Some(a / b)
+            }
+        }
+    }
+    assert_eq!(checked_div(12, 4), Some(3));
+    assert_eq!(checked_div(12, 0), None);
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn noreturn() {
+    // This is only type-checked, since running it would crash the test.
+    fn trap(code: u32) -> ! {
+        unsafe {
+            rusty_asm! {
+                let _code: in("{eax}") = code;
+                asm("volatile", noreturn) {
+                    "ud2"
+                }
+            }
+        }
+    }
+    let _: fn(u32) -> ! = trap;
+}
+
 // TODO: This test can be uncommented whenever compiletest_rs starts expanding macros.
 /*#[test]
 fn compile_fail() {